@@ -0,0 +1,124 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::domain::now;
+
+/// Snapshots kept in a backup directory when no other count is given
+pub const DEFAULT_KEEP: usize = 10;
+/// Starts the name of the snapshots taken before a destructive action, so
+/// they are rotated apart from the backups the user made
+const AUTOMATIC_PREFIX: &str = "auto-";
+
+/// A new file in `directory` named after the db and the current time, e.g.
+/// `auto-Server-20201024-134501-remove.store` for a snapshot taken before a remove
+pub fn snapshot_path(directory: &Path, db: &Path, reason: Option<&str>) -> PathBuf {
+    let (stem, extension) = name_parts(db);
+    let prefix = if reason.is_some() { AUTOMATIC_PREFIX } else { "" };
+    let reason = reason.map(|reason| format!("-{}", reason)).unwrap_or_default();
+    let base = format!("{}{}-{}{}", prefix, stem, timestamp(now()), reason);
+    let mut path = directory.join(format!("{}.{}", base, extension));
+    let mut counter = 1;
+    while path.exists() {
+        path = directory.join(format!("{}-{}.{}", base, counter, extension));
+        counter += 1;
+    }
+    path
+}
+
+/// Deletes the oldest snapshots of `db` in `directory` beyond the newest
+/// `keep`, only among the automatic ones or only among the others. Returns
+/// the deleted files.
+pub fn rotate(directory: &Path, db: &Path, automatic: bool, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let (stem, extension) = name_parts(db);
+    let prefix = format!("{}{}-", if automatic { AUTOMATIC_PREFIX } else { "" }, stem);
+    let suffix = format!(".{}", extension);
+    let mut snapshots = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(&prefix)
+                        && name.ends_with(&suffix)
+                        && name[prefix.len()..].starts_with(|c: char| c.is_ascii_digit())
+                })
+        })
+        .map(|path| (fs::metadata(&path).and_then(|metadata| metadata.modified()).ok(), path))
+        .collect::<Vec<_>>();
+    // Oldest first, by the time in the name when several share a modification time
+    snapshots.sort();
+    let snapshots: Vec<PathBuf> = snapshots.into_iter().map(|(_, path)| path).collect();
+    let excess = snapshots.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = snapshots.into_iter().take(excess).collect();
+    for path in &removed {
+        fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
+
+fn name_parts(db: &Path) -> (String, String) {
+    let part = |value: Option<&std::ffi::OsStr>| value.map(|value| value.to_string_lossy().into_owned());
+    (
+        part(db.file_stem()).unwrap_or_else(|| "backup".to_owned()),
+        part(db.extension()).unwrap_or_else(|| "store".to_owned()),
+    )
+}
+
+/// Formats a unix timestamp as `YYYYMMDD-HHMMSS` in UTC
+pub fn timestamp(seconds: i64) -> String {
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[test]
+fn snapshot_rotation_test() {
+    assert_eq!(timestamp(0), "19700101-000000");
+    assert_eq!(timestamp(1603547101), "20201024-134501");
+    assert_eq!(timestamp(951782400), "20000229-000000");
+
+    let directory = std::env::temp_dir().join(format!("rusteze-backup-test-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let db = Path::new("Server.store");
+    for name in [
+        "Server-20200101-000000.store",
+        "Server-20200102-000000.store",
+        "Server-20200103-000000.store",
+        "auto-Server-20200104-000000-remove.store",
+        "auto-Server-20200105-000000-import.store",
+        "Other-20200101-000000.store",
+    ] {
+        fs::write(directory.join(name), "").unwrap();
+    }
+    let next = snapshot_path(&directory, db, Some("import"));
+    let name = next.file_name().unwrap().to_string_lossy().into_owned();
+    assert!(name.starts_with("auto-Server-") && name.ends_with("-import.store"));
+
+    let removed = rotate(&directory, db, false, 2).unwrap();
+    assert_eq!(removed, vec![directory.join("Server-20200101-000000.store")]);
+    assert!(directory.join("Other-20200101-000000.store").exists());
+    // Automatic snapshots never take the place of the backups made by hand
+    let removed = rotate(&directory, db, true, 1).unwrap();
+    assert_eq!(removed, vec![directory.join("auto-Server-20200104-000000-remove.store")]);
+    assert!(directory.join("Server-20200102-000000.store").exists());
+    fs::remove_dir_all(&directory).unwrap();
+}
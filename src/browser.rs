@@ -0,0 +1,468 @@
+use std::io::{self, stdout};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{enable_raw_mode, EnterAlternateScreen};
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Clear, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use rusteze::domain::{Action, ActionErr, ActionErrType, ActionResponse, Server, ServerQuery, ID};
+use crate::picker::Picker;
+
+/// What the browser needs from the application, the handler wiring it to
+/// the same service layer as the commands
+pub trait Session {
+    fn route(&self, action: Action) -> Result<ActionResponse, ActionErr>;
+    /// The checks a server entered in the form has to pass before saving
+    fn validate(&self, server: Server) -> Result<Server, ActionErr>;
+    /// Connects to a server, the terminal belonging to ssh meanwhile
+    fn connect(&self, id: ID) -> Result<(), ActionErr>;
+}
+
+const FORM_FIELDS: [&str; 6] = ["title", "alias", "domain", "ip", "port", "user"];
+
+/// The add and edit form, one line per field
+struct Form {
+    /// The server being edited, None when adding one
+    server: Option<Server>,
+    values: Vec<String>,
+    focus: usize,
+}
+
+impl Form {
+    fn add() -> Form {
+        Form {
+            server: None,
+            values: vec![String::new(); FORM_FIELDS.len()],
+            focus: 0,
+        }
+    }
+
+    fn edit(server: &Server) -> Form {
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+        Form {
+            values: vec![
+                server.title.to_owned(),
+                text(&server.alias),
+                text(&server.domain),
+                text(&server.ip),
+                server.port.map(|port| port.to_string()).unwrap_or_default(),
+                server.user_name.to_owned(),
+            ],
+            server: Some(server.clone()),
+            focus: 0,
+        }
+    }
+
+    /// The server as entered, attributes and notes kept from the edited one
+    fn server(&self) -> Result<Server, ActionErr> {
+        let value = |index: usize| self.values[index].trim().to_owned();
+        let port = match value(4) {
+            port if port.is_empty() => None,
+            port => Some(port.parse::<u16>().map_err(|why| {
+                ActionErr::build(ActionErrType::InvalidInput(format!("Invalid port {}", port))).with_source(why)
+            })?),
+        };
+        let base = self
+            .server
+            .clone()
+            .unwrap_or_else(|| Server::new(String::new(), None, None, String::new()));
+        Ok(Server {
+            title: value(0),
+            alias: Some(value(1)),
+            domain: Some(value(2)),
+            ip: Some(value(3)),
+            port,
+            user_name: value(5),
+            ..base
+        })
+    }
+}
+
+enum Mode {
+    Browse,
+    Search,
+    Form(Box<Form>),
+    /// Attributes being typed, `name=value` to set and `-name` to remove
+    Tag(String),
+    /// Waiting for y to remove the selected server
+    Remove,
+}
+
+/// What the event loop does after a key
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    Stay,
+    Quit,
+    Connect(ID),
+}
+
+/// The inventory on the full terminal, servers on the left and the details
+/// of the selected one on the right
+pub struct Browser<'a> {
+    session: &'a dyn Session,
+    picker: Picker,
+    details: fn(&Server) -> String,
+    mode: Mode,
+    status: String,
+}
+
+impl<'a> Browser<'a> {
+    pub fn new(session: &'a dyn Session, label: fn(&Server) -> String, details: fn(&Server) -> String) -> Result<Browser<'a>, ActionErr> {
+        Ok(Browser {
+            picker: Picker::new(fetch(session)?, label),
+            session,
+            details,
+            mode: Mode::Browse,
+            status: String::new(),
+        })
+    }
+
+    fn refresh(&mut self) {
+        match fetch(self.session) {
+            Ok(servers) => self.picker.reload(servers),
+            Err(why) => self.status = why.to_string(),
+        }
+    }
+
+    /// Reports the outcome of an action in the status line, reloading the
+    /// servers when it succeeded
+    fn report(&mut self, result: Result<ActionResponse, ActionErr>, done: String) -> bool {
+        match result {
+            Ok(_) => {
+                self.status = done;
+                self.refresh();
+                true
+            }
+            Err(why) => {
+                self.status = why.to_string();
+                false
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Step {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Step::Quit;
+        }
+        let mode = std::mem::replace(&mut self.mode, Mode::Browse);
+        self.mode = match mode {
+            Mode::Browse => return self.browse_key(key),
+            Mode::Search => self.search_key(key),
+            Mode::Form(form) => self.form_key(form, key),
+            Mode::Tag(input) => self.tag_key(input, key),
+            Mode::Remove => self.remove_key(key),
+        };
+        Step::Stay
+    }
+
+    fn browse_key(&mut self, key: KeyEvent) -> Step {
+        self.status.clear();
+        let selected = self.picker.selected().cloned();
+        match (key.code, selected) {
+            (KeyCode::Esc, _) if !self.picker.query().is_empty() => self.picker.clear(),
+            (KeyCode::Char('q'), _) | (KeyCode::Esc, _) => return Step::Quit,
+            (KeyCode::Up, _) | (KeyCode::Char('k'), _) => self.picker.up(),
+            (KeyCode::Down, _) | (KeyCode::Char('j'), _) => self.picker.down(),
+            (KeyCode::Char('/'), _) => self.mode = Mode::Search,
+            (KeyCode::Char('a'), _) => self.mode = Mode::Form(Box::new(Form::add())),
+            (KeyCode::Char('r'), _) => self.refresh(),
+            (KeyCode::Enter, Some(server)) | (KeyCode::Char('c'), Some(server)) => {
+                if let Some(id) = server.id {
+                    return Step::Connect(id.into());
+                }
+            }
+            (KeyCode::Char('e'), Some(server)) => self.mode = Mode::Form(Box::new(Form::edit(&server))),
+            (KeyCode::Char('t'), Some(_)) => self.mode = Mode::Tag(String::new()),
+            (KeyCode::Char('d'), Some(_)) | (KeyCode::Delete, Some(_)) => self.mode = Mode::Remove,
+            _ => (),
+        }
+        Step::Stay
+    }
+
+    fn search_key(&mut self, key: KeyEvent) -> Mode {
+        match key.code {
+            KeyCode::Enter => return Mode::Browse,
+            KeyCode::Esc => {
+                self.picker.clear();
+                return Mode::Browse;
+            }
+            KeyCode::Up => self.picker.up(),
+            KeyCode::Down => self.picker.down(),
+            KeyCode::Backspace => self.picker.pop(),
+            KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => self.picker.clear(),
+            KeyCode::Char(character) => self.picker.push(character),
+            _ => (),
+        }
+        Mode::Search
+    }
+
+    fn form_key(&mut self, mut form: Box<Form>, key: KeyEvent) -> Mode {
+        match key.code {
+            KeyCode::Esc => return Mode::Browse,
+            KeyCode::Tab | KeyCode::Down => form.focus = (form.focus + 1) % FORM_FIELDS.len(),
+            KeyCode::BackTab | KeyCode::Up => form.focus = (form.focus + FORM_FIELDS.len() - 1) % FORM_FIELDS.len(),
+            KeyCode::Backspace => {
+                form.values[form.focus].pop();
+            }
+            KeyCode::Char(character) => form.values[form.focus].push(character),
+            KeyCode::Enter => {
+                let server = form.server().and_then(|server| self.session.validate(server));
+                let saved = match (server, form.server.as_ref().and_then(|server| server.id)) {
+                    (Ok(server), Some(id)) => {
+                        let done = format!("Updated {}", server.title);
+                        self.report(self.session.route(Action::Update(id.into(), server)), done)
+                    }
+                    (Ok(server), None) => {
+                        let done = format!("Added {}", server.title);
+                        self.report(self.session.route(Action::Save(server)), done)
+                    }
+                    (Err(why), _) => {
+                        self.status = why.to_string();
+                        false
+                    }
+                };
+                if saved {
+                    return Mode::Browse;
+                }
+            }
+            _ => (),
+        }
+        Mode::Form(form)
+    }
+
+    fn tag_key(&mut self, mut input: String, key: KeyEvent) -> Mode {
+        match key.code {
+            KeyCode::Esc => return Mode::Browse,
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char(character) => input.push(character),
+            KeyCode::Enter if self.apply_tags(&input) => return Mode::Browse,
+            _ => (),
+        }
+        Mode::Tag(input)
+    }
+
+    /// Sets the `name=value` attributes and removes the `-name` ones of the
+    /// selected server
+    fn apply_tags(&mut self, input: &str) -> bool {
+        let id = match self.picker.selected().and_then(|server| server.id) {
+            Some(id) => ID::from(id),
+            None => return true,
+        };
+        let mut set = Vec::new();
+        let mut remove = Vec::new();
+        for word in input.split_whitespace() {
+            match (word.strip_prefix('-'), word.split_once('=')) {
+                (Some(name), _) if !name.is_empty() => remove.push(name.to_owned()),
+                (None, Some((name, value))) if !name.is_empty() => set.push((name.to_owned(), value.to_owned())),
+                _ => {
+                    self.status = format!("Invalid {}, use name=value to set and -name to remove", word);
+                    return false;
+                }
+            }
+        }
+        let done = format!("{} set, {} removed", set.len(), remove.len());
+        if !set.is_empty() && !self.report(self.session.route(Action::SetAttributes(id, set)), done.clone()) {
+            return false;
+        }
+        remove.is_empty() || self.report(self.session.route(Action::RemoveAttributes(id, remove)), done)
+    }
+
+    fn remove_key(&mut self, key: KeyEvent) -> Mode {
+        if let (KeyCode::Char('y'), Some(server)) = (key.code, self.picker.selected().cloned()) {
+            let done = format!("Removed {}, rusteze restore brings it back", server.title);
+            let id = ID::from(server.id.unwrap_or_default());
+            self.report(self.session.route(Action::DeleteById(id)), done);
+        }
+        Mode::Browse
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [prompt, body, status, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [list, details] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+
+        self.picker.draw_prompt(frame, prompt);
+        self.picker.draw_list(frame, list);
+        let text = self.picker.selected().map(self.details).unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(text)
+                .block(Block::bordered().title(" Details "))
+                .wrap(Wrap { trim: false }),
+            details,
+        );
+
+        let (line, keys) = match &self.mode {
+            Mode::Browse => (
+                Line::from(self.status.as_str()),
+                "/ search  enter connect  a add  e edit  t tag  d remove  r reload  q quit",
+            ),
+            Mode::Search => (Line::from(self.status.as_str()), "type to filter  enter keep the filter  esc clear it"),
+            Mode::Form(form) => {
+                draw_form(frame, form);
+                (Line::from(self.status.as_str()), "tab next field  enter save  esc cancel")
+            }
+            Mode::Tag(input) => (
+                Line::from(format!("Tags: {}_", input)),
+                "name=value to set, -name to remove, separated by spaces  enter apply  esc cancel",
+            ),
+            Mode::Remove => (
+                Line::from(format!(
+                    "Remove {}? y/n",
+                    self.picker.selected().map(|server| server.title.as_str()).unwrap_or_default()
+                ))
+                .bold(),
+                "y remove  any other key cancel",
+            ),
+        };
+        frame.render_widget(line, status);
+        frame.render_widget(Line::from(keys).dim(), help);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            match self.handle_key(key) {
+                Step::Stay => (),
+                Step::Quit => return Ok(()),
+                Step::Connect(id) => {
+                    ratatui::try_restore()?;
+                    let connected = self.session.connect(id);
+                    enable_raw_mode()?;
+                    execute!(stdout(), EnterAlternateScreen)?;
+                    terminal.clear()?;
+                    // Also brings the new last connected time
+                    self.report(connected.map(|_| ActionResponse::Done), "Connected".to_owned());
+                }
+            }
+        }
+    }
+}
+
+fn draw_form(frame: &mut Frame, form: &Form) {
+    let [area] = Layout::horizontal([Constraint::Length(60)])
+        .flex(Flex::Center)
+        .areas::<1>(frame.area());
+    let [area]: [Rect; 1] = Layout::vertical([Constraint::Length(FORM_FIELDS.len() as u16 + 2)])
+        .flex(Flex::Center)
+        .areas(area);
+    let lines = FORM_FIELDS
+        .iter()
+        .zip(&form.values)
+        .enumerate()
+        .map(|(index, (name, value))| {
+            let line = Line::from(format!("{:<8}{}", name, value));
+            if index == form.focus {
+                Line::from(format!("{:<8}{}_", name, value)).style(Style::default().add_modifier(Modifier::BOLD))
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>();
+    let title = match &form.server {
+        Some(server) => format!(" Edit {} ", server.title),
+        None => " Add server ".to_owned(),
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), area);
+}
+
+fn fetch(session: &dyn Session) -> Result<Vec<Server>, ActionErr> {
+    match session.route(Action::Fetch(ServerQuery::default()))? {
+        ActionResponse::All(servers) => Ok(servers),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Runs the browser until the user quits
+pub fn browse(session: &dyn Session, label: fn(&Server) -> String, details: fn(&Server) -> String) -> Result<(), ActionErr> {
+    let failed = |why: io::Error| {
+        ActionErr::build(ActionErrType::ActionFailed("Unable to run the terminal interface".to_owned())).with_source(why)
+    };
+    let mut browser = Browser::new(session, label, details)?;
+    let mut terminal = ratatui::try_init().map_err(failed)?;
+    let result = browser.run(&mut terminal);
+    ratatui::try_restore().map_err(failed)?;
+    result.map_err(failed)
+}
+
+#[cfg(test)]
+struct MemorySession {
+    repository: rusteze::MemoryRepository,
+    connected: std::cell::RefCell<Vec<ID>>,
+}
+
+#[cfg(test)]
+impl Session for MemorySession {
+    fn route(&self, action: Action) -> Result<ActionResponse, ActionErr> {
+        rusteze::service::route(&self.repository, rusteze::domain::DEFAULT_USER, action)
+    }
+
+    fn validate(&self, server: Server) -> Result<Server, ActionErr> {
+        match server.title.is_empty() {
+            true => Err(ActionErr::build(ActionErrType::InvalidInput("Title is required".to_owned()))),
+            false => Ok(server),
+        }
+    }
+
+    fn connect(&self, id: ID) -> Result<(), ActionErr> {
+        self.connected.borrow_mut().push(id);
+        Ok(())
+    }
+}
+
+#[test]
+fn browser_keys_test() {
+    let session = MemorySession {
+        repository: rusteze::MemoryRepository::new(),
+        connected: std::cell::RefCell::new(Vec::new()),
+    };
+    let server = Server::new("web".to_owned(), Some("web.example.com".to_owned()), None, "admin".to_owned());
+    session.route(Action::Save(server)).unwrap();
+    let mut browser = Browser::new(&session, |server| server.title.to_owned(), |server| server.title.to_owned()).unwrap();
+    let mut press = |keys: &str| {
+        keys.chars()
+            .map(|key| {
+                let code = match key {
+                    '\n' => KeyCode::Enter,
+                    '\t' => KeyCode::Tab,
+                    '\x1b' => KeyCode::Esc,
+                    key => KeyCode::Char(key),
+                };
+                browser.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+            })
+            .last()
+    };
+
+    press("adb\t\t\t10.0.0.9\t\troot\n");
+    press("/db\n");
+    press("tdc=ams role=primary\n");
+    press("t-role\n");
+    press("e\tdb\n");
+    assert_eq!(press("\n"), Some(Step::Connect(2)));
+    let db = match session.route(Action::FetchById(2)).unwrap() {
+        ActionResponse::One(Some(server)) => server,
+        other => panic!("unexpected response {:?}", other),
+    };
+    assert_eq!(db.alias.as_deref(), Some("db"));
+    assert_eq!(db.attributes.into_iter().collect::<Vec<_>>(), vec![("dc".to_owned(), "ams".to_owned())]);
+
+    press("dy");
+    press("\x1b");
+    assert_eq!(press("\n"), Some(Step::Connect(1)));
+    assert_eq!(press("q"), Some(Step::Quit));
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeSet;
+
+use clap::{App, AppSettings, Arg, ArgSettings};
+
+use rusteze::domain::Server;
+
+/// Name of the hidden subcommand the scripts call for server ids, titles and tags
+pub const COMPLETE_COMMAND: &str = "__complete";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn parse(name: &str) -> Option<Shell> {
+        match name.trim() {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// What `__complete` lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Candidates {
+    /// Ids, aliases and titles, anything `-i` resolves
+    Servers,
+    /// Attributes as `name=value`, what `--attr` filters on
+    Tags,
+}
+
+impl Candidates {
+    pub fn parse(name: &str) -> Option<Candidates> {
+        match name.trim() {
+            "servers" => Some(Candidates::Servers),
+            "tags" => Some(Candidates::Tags),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Candidates::Servers => "servers",
+            Candidates::Tags => "tags",
+        }
+    }
+}
+
+/// The values of `kind`, one per server field and without duplicates
+pub fn candidates(servers: &[Server], kind: Candidates) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    let values: Vec<String> = match kind {
+        Candidates::Servers => servers
+            .iter()
+            .flat_map(|server| {
+                let id = server.id.map(|id| id.to_string());
+                id.into_iter().chain(server.alias.clone()).chain(Some(server.title.to_owned()))
+            })
+            .collect(),
+        Candidates::Tags => servers
+            .iter()
+            .flat_map(|server| server.attributes.iter().map(|(name, value)| format!("{}={}", name, value)))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect(),
+    };
+    values
+        .into_iter()
+        .filter(|value| !value.trim().is_empty() && seen.insert(value.to_owned()))
+        .collect()
+}
+
+/// Values an option or a positional argument takes
+#[derive(Debug, Clone, PartialEq)]
+enum Values {
+    Fixed(Vec<String>),
+    Dynamic(Candidates),
+    Files,
+    Any,
+}
+
+#[derive(Debug)]
+struct Opt {
+    short: Option<char>,
+    long: Option<String>,
+    about: String,
+    /// None for a flag
+    values: Option<Values>,
+}
+
+impl Opt {
+    fn words(&self) -> Vec<String> {
+        let short = self.short.map(|short| format!("-{}", short));
+        let long = self.long.as_ref().map(|long| format!("--{}", long));
+        short.into_iter().chain(long).collect()
+    }
+}
+
+/// A command or subcommand with what may follow it
+#[derive(Debug)]
+struct Command {
+    /// Usable as a shell function or variable name, e.g. rusteze__attr__set
+    id: String,
+    /// Name, alias or not, and the id of the subcommand
+    subcommands: Vec<(String, String, String)>,
+    options: Vec<Opt>,
+    positionals: Vec<Values>,
+}
+
+impl Command {
+    fn subcommand_names(&self) -> Vec<&str> {
+        self.subcommands.iter().map(|(name, _, _)| name.as_str()).collect()
+    }
+
+    fn option_words(&self) -> Vec<String> {
+        self.options.iter().flat_map(Opt::words).collect()
+    }
+
+    /// The values of the first positional argument, the only one completed
+    fn positional(&self) -> Option<&Values> {
+        self.positionals.first()
+    }
+}
+
+fn values(arg: &Arg) -> Values {
+    if let Some(values) = arg.get_possible_values() {
+        return Values::Fixed(values.iter().map(|value| value.to_string()).collect());
+    }
+    match arg.get_name() {
+        "input" => Values::Dynamic(Candidates::Servers),
+        "attr" => Values::Dynamic(Candidates::Tags),
+        "file" | "path" | "home" => Values::Files,
+        _ => Values::Any,
+    }
+}
+
+/// Flattens the app into its commands, hidden ones left out
+fn commands(app: &App, id: String, commands: &mut Vec<Command>) {
+    let visible = app
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_set(AppSettings::Hidden))
+        .collect::<Vec<_>>();
+    let subcommands = visible
+        .iter()
+        .flat_map(|subcommand| {
+            let child = format!("{}__{}", id, subcommand.get_name().replace('-', "_"));
+            let about = subcommand.get_about().unwrap_or_default().to_owned();
+            std::iter::once(subcommand.get_name())
+                .chain(subcommand.get_visible_aliases())
+                .map(move |name| (name.to_owned(), child.clone(), about.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let options = app
+        .get_arguments()
+        .filter(|arg| arg.get_index().is_none() && (arg.get_short().is_some() || arg.get_long().is_some()))
+        .map(|arg| Opt {
+            short: arg.get_short(),
+            long: arg.get_long().map(str::to_owned),
+            about: arg.get_about().unwrap_or_default().to_owned(),
+            values: if arg.is_set(ArgSettings::TakesValue) { Some(values(arg)) } else { None },
+        })
+        .collect();
+    let positionals = app.get_positionals().map(values).collect();
+    commands.push(Command {
+        id: id.clone(),
+        subcommands,
+        options,
+        positionals,
+    });
+    for subcommand in visible {
+        let child = format!("{}__{}", id, subcommand.get_name().replace('-', "_"));
+        self::commands(subcommand, child, commands);
+    }
+}
+
+/// A completion script for `shell`, generated from the commands of `app`
+pub fn script(app: &App, shell: Shell) -> String {
+    let bin = app.get_name().to_owned();
+    let mut all = Vec::new();
+    commands(app, bin.replace('-', "_"), &mut all);
+    match shell {
+        Shell::Bash => bash(&bin, &all),
+        Shell::Zsh => zsh(&bin, &all),
+        Shell::Fish => fish(&bin, &all),
+    }
+}
+
+/// Case arms walking from a command to the subcommand named by a word
+fn transitions(commands: &[Command], arm: impl Fn(&str, &str, &str) -> String) -> String {
+    let mut out = String::new();
+    for command in commands {
+        for (name, child, _) in &command.subcommands {
+            out += &arm(&command.id, name, child);
+        }
+    }
+    out
+}
+
+fn bash(bin: &str, commands: &[Command]) -> String {
+    let dynamic = |kind: Candidates| {
+        format!(
+            "local IFS=$'\\n'; COMPREPLY=($(compgen -W \"$({} {} {} 2>/dev/null)\" -- \"${{cur}}\"))",
+            bin,
+            COMPLETE_COMMAND,
+            kind.name()
+        )
+    };
+    let reply = |values: &Values| match values {
+        Values::Fixed(values) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"${{cur}}\"))", values.join(" ")),
+        Values::Dynamic(kind) => dynamic(*kind),
+        Values::Files => "COMPREPLY=($(compgen -f -- \"${cur}\"))".to_owned(),
+        Values::Any => "COMPREPLY=()".to_owned(),
+    };
+    let mut out = format!(
+        "_{bin}() {{\n    local cur prev word cmd\n    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    cmd=\"{id}\"\n    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do\n        case \"${{cmd}}:${{word}}\" in\n",
+        bin = bin.replace('-', "_"),
+        id = commands[0].id
+    );
+    out += &transitions(commands, |id, name, child| {
+        format!("            \"{}:{}\") cmd=\"{}\" ;;\n", id, name, child)
+    });
+    out += "        esac\n    done\n    case \"${cmd}\" in\n";
+    for command in commands {
+        out += &format!("        {})\n", command.id);
+        let valued = command
+            .options
+            .iter()
+            .filter_map(|opt| opt.values.as_ref().map(|values| (opt.words().join("|"), values)))
+            .collect::<Vec<_>>();
+        if !valued.is_empty() {
+            out += "            case \"${prev}\" in\n";
+            for (words, values) in valued {
+                out += &format!("                {}) {}; return ;;\n", words, reply(values));
+            }
+            out += "            esac\n";
+        }
+        if let Some(values) = command.positional() {
+            out += &format!("            if [[ \"${{cur}}\" != -* ]]; then {}; return; fi\n", reply(values));
+        }
+        let words = command
+            .subcommand_names()
+            .into_iter()
+            .map(str::to_owned)
+            .chain(command.option_words())
+            .collect::<Vec<_>>();
+        out += &format!("            COMPREPLY=($(compgen -W \"{}\" -- \"${{cur}}\"))\n            ;;\n", words.join(" "));
+    }
+    out += &format!("    esac\n}}\n\ncomplete -F _{} {}\n", bin.replace('-', "_"), bin);
+    out
+}
+
+fn zsh(bin: &str, commands: &[Command]) -> String {
+    let reply = |values: &Values| match values {
+        Values::Fixed(values) => format!("compadd -- {}", values.join(" ")),
+        Values::Dynamic(kind) => format!(
+            "compadd -- ${{(f)\"$({} {} {} 2>/dev/null)\"}}",
+            bin,
+            COMPLETE_COMMAND,
+            kind.name()
+        ),
+        Values::Files => "_files".to_owned(),
+        Values::Any => "_message value".to_owned(),
+    };
+    // `path` is tied to $PATH in zsh, hence `cmd`
+    let mut out = format!(
+        "#compdef {bin}\n\n_{name}() {{\n    local cmd=\"{id}\" word prev=\"${{words[CURRENT-1]}}\"\n    for word in \"${{(@)words[2,CURRENT-1]}}\"; do\n        case \"${{cmd}}:${{word}}\" in\n",
+        bin = bin,
+        name = bin.replace('-', "_"),
+        id = commands[0].id
+    );
+    out += &transitions(commands, |id, name, child| {
+        format!("            \"{}:{}\") cmd=\"{}\" ;;\n", id, name, child)
+    });
+    out += "        esac\n    done\n    case \"${cmd}\" in\n";
+    for command in commands {
+        out += &format!("        {})\n", command.id);
+        let valued = command
+            .options
+            .iter()
+            .filter_map(|opt| opt.values.as_ref().map(|values| (opt.words().join("|"), values)))
+            .collect::<Vec<_>>();
+        if !valued.is_empty() {
+            out += "            case \"${prev}\" in\n";
+            for (words, values) in valued {
+                out += &format!("                {}) {}; return ;;\n", words, reply(values));
+            }
+            out += "            esac\n";
+        }
+        if let Some(values) = command.positional() {
+            out += &format!("            if [[ \"${{PREFIX}}\" != -* ]]; then {}; return; fi\n", reply(values));
+        }
+        let names = command.subcommand_names();
+        if !names.is_empty() {
+            out += &format!("            compadd -- {}\n", names.join(" "));
+        }
+        let options = command.option_words();
+        if !options.is_empty() {
+            out += &format!("            compadd -- {}\n", options.join(" "));
+        }
+        out += "            ;;\n";
+    }
+    out += &format!("    esac\n}}\n\ncompdef _{} {}\n", bin.replace('-', "_"), bin);
+    out
+}
+
+fn fish(bin: &str, commands: &[Command]) -> String {
+    let quote = |text: &str| format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"));
+    let helper = format!("__{}_command", bin.replace('-', "_"));
+    let mut out = format!(
+        "function {helper}\n    set -l cmd {id}\n    for word in (commandline -opc)[2..-1]\n        switch \"$cmd:$word\"\n",
+        helper = helper,
+        id = commands[0].id
+    );
+    out += &transitions(commands, |id, name, child| {
+        format!("            case \"{}:{}\"\n                set cmd {}\n", id, name, child)
+    });
+    out += &format!("        end\n    end\n    echo $cmd\nend\n\ncomplete -c {} -f\n", bin);
+    for command in commands {
+        let condition = format!("-n 'test ({}) = {}'", helper, command.id);
+        for (name, _, about) in &command.subcommands {
+            out += &format!("complete -c {} {} -a {} -d {}\n", bin, condition, name, quote(about));
+        }
+        for opt in &command.options {
+            let mut line = format!("complete -c {} {}", bin, condition);
+            if let Some(short) = opt.short {
+                line += &format!(" -s {}", short);
+            }
+            if let Some(long) = &opt.long {
+                line += &format!(" -l {}", long);
+            }
+            match &opt.values {
+                Some(Values::Fixed(values)) => line += &format!(" -x -a {}", quote(&values.join(" "))),
+                Some(Values::Dynamic(kind)) => {
+                    line += &format!(" -x -a '({} {} {} 2>/dev/null)'", bin, COMPLETE_COMMAND, kind.name())
+                }
+                Some(Values::Files) => line += " -r -F",
+                Some(Values::Any) => line += " -x",
+                None => (),
+            }
+            out += &format!("{} -d {}\n", line, quote(&opt.about));
+        }
+        match command.positional() {
+            Some(Values::Fixed(values)) => {
+                out += &format!("complete -c {} {} -a {}\n", bin, condition, quote(&values.join(" ")))
+            }
+            Some(Values::Files) => out += &format!("complete -c {} {} -F\n", bin, condition),
+            _ => (),
+        }
+    }
+    out
+}
+
+#[test]
+fn script_test() {
+    let app = crate::handler::command_line();
+    let bash = script(&app, Shell::Bash);
+    assert!(bash.contains("\"rusteze:connect\") cmd=\"rusteze__connect\" ;;"));
+    assert!(bash.contains("\"rusteze__attr:set\") cmd=\"rusteze__attr__set\" ;;"));
+    assert!(bash.contains("-i|--input) local IFS=$'\\n'; COMPREPLY=($(compgen -W \"$(rusteze __complete servers"));
+    assert!(bash.contains("--attr) local IFS=$'\\n'; COMPREPLY=($(compgen -W \"$(rusteze __complete tags"));
+    assert!(!bash.contains("__complete)"));
+    assert!(script(&app, Shell::Zsh).starts_with("#compdef rusteze\n"));
+    assert!(script(&app, Shell::Fish).contains("-n 'test (__rusteze_command) = rusteze__connect' -s i -l input -x"));
+}
+
+#[test]
+fn candidates_test() {
+    let mut web = Server::new("web".to_owned(), Some("web.example.com".to_owned()), None, "admin".to_owned());
+    web.id = Some(1);
+    web.alias = Some("w".to_owned());
+    web.attributes.insert("dc".to_owned(), "ams".to_owned());
+    let mut db = Server::new("db".to_owned(), None, Some("10.0.0.9".to_owned()), "root".to_owned());
+    db.id = Some(2);
+    db.attributes.insert("dc".to_owned(), "ams".to_owned());
+    db.attributes.insert("role".to_owned(), "primary".to_owned());
+    let servers = [web, db];
+
+    assert_eq!(candidates(&servers, Candidates::Servers), vec!["1", "w", "web", "2", "db"]);
+    assert_eq!(candidates(&servers, Candidates::Tags), vec!["dc=ams", "role=primary"]);
+}
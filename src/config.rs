@@ -2,8 +2,13 @@
 use cfg_if::cfg_if;
 use log::info;
 
-use std::fs::File;
-use super::domain::{Setup, ActionErr, ActionErrType, ActionResponse};
+use serde::{Deserialize, Serialize};
+use toml_edit::DocumentMut;
+use std::fs::{self, File};
+use super::domain::{
+    Backend, Hooks, Launcher, LogLevel, OutputFormat, Setup, ActionErr, ActionErrType, ActionResponse,
+};
+use super::paths;
 
 
 cfg_if! {
@@ -14,7 +19,126 @@ cfg_if! {
     }
 }
 
-pub const CONFIG_FILE: &str = "app.conf";
+/// The keys of app.conf, a TOML file. Every key is optional and falls back
+/// to its default.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConfigFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<Backend>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub launcher: Option<Launcher>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<LogLevel>,
+    #[serde(skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+}
+
+impl ConfigFile {
+    /// Parses the configuration, along with a warning for every key it does
+    /// not know. Files written by releases before TOML are still read.
+    pub fn parse(content: &str) -> Result<(ConfigFile, Vec<String>), toml::de::Error> {
+        match parse_toml(content) {
+            Ok(parsed) => Ok(parsed),
+            Err(why) => match upgrade_legacy(content).map(|upgraded| parse_toml(&upgraded)) {
+                Some(Ok((config, mut warnings))) => {
+                    warnings.push(
+                        "the configuration uses the old key=value format, `rusteze --set` saves it as TOML"
+                            .to_owned(),
+                    );
+                    Ok((config, warnings))
+                }
+                _ => Err(why),
+            },
+        }
+    }
+}
+
+/// Keys that `rusteze config get` and `set` accept, tables joined with a dot
+pub const KEYS: [&str; 8] = [
+    "db",
+    "user",
+    "backend",
+    "launcher",
+    "output",
+    "log",
+    "hooks.pre_connect",
+    "hooks.post_connect",
+];
+
+/// Sets `key` to `value` in the configuration `content`, keeping its comments
+/// and layout, and checks the result still loads
+pub fn set_key(content: &str, key: &str, value: &str) -> Result<String, ActionErr> {
+    if !KEYS.contains(&key) {
+        return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+            "Unknown key {}, expected one of {}",
+            key,
+            KEYS.join(", ")
+        ))));
+    }
+    let mut document = match content.parse::<DocumentMut>() {
+        Ok(document) => document,
+        // An old key=value file is saved as TOML from now on
+        Err(why) => match ConfigFile::parse(content) {
+            Ok((config, _)) => toml::to_string(&config)
+                .map_err(|why| invalid_config(why.to_string()))?
+                .parse::<DocumentMut>()
+                .map_err(|why| invalid_config(why.to_string()))?,
+            Err(_) => return Err(invalid_config(why.to_string())),
+        },
+    };
+    match key.split_once('.') {
+        Some((table, name)) => {
+            if !document.contains_key(table) {
+                document[table] = toml_edit::table();
+            }
+            document[table][name] = toml_edit::value(value);
+        }
+        None => document[key] = toml_edit::value(value),
+    }
+    let updated = document.to_string();
+    match ConfigFile::parse(&updated) {
+        Ok(_) => Ok(updated),
+        Err(why) => Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+            "Invalid value for {}",
+            key
+        )))
+        .with_source(why.message().to_owned())),
+    }
+}
+
+fn invalid_config(why: String) -> ActionErr {
+    ActionErr::build(ActionErrType::InvalidInput("The configuration does not parse".to_owned())).with_source(why)
+}
+
+fn parse_toml(content: &str) -> Result<(ConfigFile, Vec<String>), toml::de::Error> {
+    let mut warnings = Vec::new();
+    let config = serde_ignored::deserialize(toml::Deserializer::new(content), |key| {
+        warnings.push(format!("unknown key `{}` is ignored", key))
+    })?;
+    Ok((config, warnings))
+}
+
+/// Quotes the bare values of the old `key=value` format, None when the
+/// content is not in that format
+fn upgrade_legacy(content: &str) -> Option<String> {
+    let mut upgraded = DocumentMut::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (key, value) = line.split_once('=')?;
+        let value = value.trim();
+        if value.starts_with(['"', '\'', '[', '{']) {
+            return None;
+        }
+        upgraded[key.trim()] = toml_edit::value(value);
+    }
+    Some(upgraded.to_string())
+}
 
 fn initialize_setup(settings: &Settings) -> Result<ActionResponse, ActionErr> {
     let result_error = initialize_config_file();
@@ -36,10 +160,16 @@ fn initialize_setup(settings: &Settings) -> Result<ActionResponse, ActionErr> {
 }
 
 fn initialize_config_file() -> Result<ActionResponse, ActionErr> {
-    if File::open(CONFIG_FILE).is_err() {
-        match File::create(CONFIG_FILE) {
+    let locations = paths::locations();
+    let config_file = locations.config_file();
+    if File::open(&config_file).is_err() {
+        let config_dir = config_file.parent().unwrap_or(&locations.config_dir);
+        let created = fs::create_dir_all(config_dir)
+            .and_then(|_| fs::create_dir_all(&locations.data_dir))
+            .and_then(|_| File::create(&config_file));
+        match created {
             Ok(_) => Ok(ActionResponse::Done),
-            _ => Err(ActionErr::build(ActionErrType::UnableToInitialize)),
+            Err(why) => Err(ActionErr::build(ActionErrType::UnableToInitialize).with_source(why)),
         }
     } else {
         info!("Config initialized");
@@ -79,3 +209,38 @@ fn load_config_test() {
     let response = Settings::load_config();
     assert!(matches!(response, _settings));
 }
+
+#[test]
+fn parse_config_test() {
+    let (config, warnings) = ConfigFile::parse(
+        "db = \"prod\"\nlauncher = \"inline\"\ncolour = true\n\n[hooks]\npre_connect = \"vpn up\"\n",
+    )
+    .unwrap();
+    assert_eq!(config.db.as_deref(), Some("prod"));
+    assert_eq!(config.launcher, Some(Launcher::Inline));
+    assert_eq!(config.hooks.pre_connect.as_deref(), Some("vpn up"));
+    assert_eq!(warnings, vec!["unknown key `colour` is ignored".to_owned()]);
+
+    let (legacy, warnings) = ConfigFile::parse("db=Server \nuser=root \nbackend=json \n").unwrap();
+    assert_eq!(legacy.user.as_deref(), Some("root"));
+    assert_eq!(legacy.backend, Some(Backend::Json));
+    assert_eq!(warnings.len(), 1);
+    let (legacy, _) = ConfigFile::parse("db=Ser\u{7f}ver\\1\n").unwrap();
+    assert_eq!(legacy.db.as_deref(), Some("Ser\u{7f}ver\\1"));
+
+    let invalid = ConfigFile::parse("db = \"prod\"\nbackend = \"mysql\"\n").unwrap_err();
+    assert!(invalid.to_string().contains("line 2"), "{}", invalid);
+}
+
+#[test]
+fn set_key_test() {
+    let content = "# my servers\ndb = \"prod\"\n";
+    let updated = set_key(content, "hooks.pre_connect", "vpn up").unwrap();
+    assert_eq!(updated, "# my servers\ndb = \"prod\"\n\n[hooks]\npre_connect = \"vpn up\"\n");
+    let updated = set_key(&updated, "db", "staging").unwrap();
+    assert!(updated.starts_with("# my servers\ndb = \"staging\"\n"));
+
+    assert!(set_key(content, "launcher", "iterm").is_err());
+    assert!(set_key(content, "colour", "red").is_err());
+    assert!(set_key("db=Server \nuser=root \n", "user", "ops").unwrap().contains("user = \"ops\""));
+}
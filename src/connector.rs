@@ -1,9 +1,205 @@
+use std::io;
 use std::process::Command;
 
-pub fn connect_terminal(user_name:String,host:String) -> std::io::Result<()>{  
+use log::{info, warn};
+
+use crate::domain::{Hooks, Launcher, Server};
+use crate::vault::Askpass;
+
+/// How to open a session: the launcher, the hooks around it and the vault
+/// answering password prompts when given
+pub struct Launch {
+    pub launcher: Launcher,
+    pub hooks: Hooks,
+    pub askpass: Option<Askpass>,
+}
+
+impl Launch {
+    /// A session in `launcher` without hooks, ssh asking for passwords itself
+    pub fn new(launcher: Launcher) -> Launch {
+        Launch {
+            launcher,
+            hooks: Hooks::default(),
+            askpass: None,
+        }
+    }
+
+    pub fn with_hooks(mut self, hooks: Hooks) -> Launch {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Answers the ssh password prompts from the vault
+    pub fn with_askpass(mut self, askpass: Askpass) -> Launch {
+        self.askpass = Some(askpass);
+        self
+    }
+}
+
+/// The arguments of ssh for `host` of `server`, the destination after `--`
+/// so that ssh never reads it as an option
+pub fn ssh_arguments(server: &Server, host: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    if let Some(port) = server.port {
+        arguments.extend(["-p".to_owned(), port.to_string()]);
+    }
+    arguments.push("--".to_owned());
+    arguments.push(format!("{}@{}", server.user_name, host));
+    arguments
+}
+
+/// Opens an ssh session to `host` of `server`, running the hooks around it. A
+/// failing `pre_connect` hook cancels the session, a failing `post_connect` is only logged.
+pub fn connect(launch: &Launch, server: &Server, host: &str) -> io::Result<()> {
+    let hook_environment = [
+        ("RUSTEZE_TITLE", server.title.as_str()),
+        ("RUSTEZE_HOST", host),
+        ("RUSTEZE_USER", server.user_name.as_str()),
+    ];
+    let arguments = ssh_arguments(server, host);
+    if let Some(command) = &launch.hooks.pre_connect {
+        info!("Running the pre_connect hook {}", command);
+        let status = Command::new("sh").arg("-c").arg(command).envs(hook_environment).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("pre_connect hook failed with {}", status)));
+        }
+    }
+    let environment = match &launch.askpass {
+        Some(askpass) => askpass.environment().map_err(|why| io::Error::other(why.to_string()))?,
+        None => Vec::new(),
+    };
+    info!(
+        "Connecting with ssh {} in the {} launcher{}",
+        arguments.join(" "),
+        launch.launcher.name(),
+        if environment.is_empty() { "" } else { ", answering password prompts from the vault" }
+    );
+    match launch.launcher {
+        Launcher::Terminal => connect_terminal(&arguments, &environment)?,
+        Launcher::Inline => {
+            let status = Command::new("ssh").args(&arguments).envs(environment).status()?;
+            info!("ssh exited with {}", status);
+        }
+    }
+    if let Some(command) = &launch.hooks.post_connect {
+        info!("Running the post_connect hook {}", command);
+        match Command::new("sh").arg("-c").arg(command).envs(hook_environment).status() {
+            Ok(status) if !status.success() => warn!("post_connect hook failed with {}", status),
+            Err(why) => warn!("Unable to run the post_connect hook {}", why),
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+fn connect_terminal(arguments: &[String], environment: &[(String, String)]) -> io::Result<()> {
+    let environment = environment
+        .iter()
+        .map(|(name, value)| format!("{}={} ", name, shell_quote(value)))
+        .collect::<String>();
+    let arguments = arguments.iter().map(|argument| shell_quote(argument)).collect::<Vec<_>>();
+    let command = format!("exec env {}ssh {}", environment, arguments.join(" "));
     Command::new("osascript")            
             .arg("-e")
-            .arg(format!("tell application \"Terminal\" to do script \"exec ssh {}@{}\"",user_name,host))
+            .arg(format!("tell application \"Terminal\" to do script \"{}\"",apple_script_escape(&command)))
             .output()?;
     Ok(())        
-}
\ No newline at end of file
+}
+
+/// Opens one tmux pane per command in a new window named `name`, tiled, with
+/// the input of one pane sent to all of them when `synchronize`. Outside of
+/// tmux the window opens in a new session, attached once every pane is there.
+pub fn open_tmux_window(name: &str, commands: &[Vec<String>], synchronize: bool) -> io::Result<()> {
+    let (first, others) = match commands.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let inside = std::env::var_os("TMUX").is_some();
+    let create = if inside { "new-window" } else { "new-session" };
+    let created = tmux(&[create, "-d", "-P", "-F", "#{session_id} #{window_id}", "-n", name, &pane_command(first)])?;
+    let (session, window) = created.trim().split_once(' ').unwrap_or_default();
+    info!("Opening {} panes in the tmux window {} of {}", commands.len(), window, session);
+    let panes = others.iter().map(|command| pane_command(command)).collect::<Vec<_>>();
+    for arguments in tmux_layout(window, &panes, synchronize) {
+        tmux(&arguments.iter().map(String::as_str).collect::<Vec<_>>())?;
+    }
+    if inside {
+        tmux(&["select-window", "-t", window])?;
+    } else {
+        let status = Command::new("tmux").args(["attach-session", "-t", session]).status()?;
+        info!("tmux exited with {}", status);
+    }
+    Ok(())
+}
+
+/// The tmux commands splitting `window` for every pane past the first, tiled
+/// again after each split so that the panes keep room for the next one
+pub fn tmux_layout(window: &str, panes: &[String], synchronize: bool) -> Vec<Vec<String>> {
+    let command = |arguments: &[&str]| arguments.iter().map(|argument| argument.to_string()).collect::<Vec<_>>();
+    let mut commands = Vec::new();
+    for pane in panes {
+        commands.push(command(&["split-window", "-t", window, pane]));
+        commands.push(command(&["select-layout", "-t", window, "tiled"]));
+    }
+    if synchronize {
+        commands.push(command(&["set-window-option", "-t", window, "synchronize-panes", "on"]));
+    }
+    commands
+}
+
+/// A command line run by the shell of a pane, its arguments quoted
+fn pane_command(command: &[String]) -> String {
+    let arguments = command.iter().map(|argument| shell_quote(argument)).collect::<Vec<_>>();
+    format!("exec {}", arguments.join(" "))
+}
+
+/// Runs tmux, returning what it printed
+fn tmux(arguments: &[&str]) -> io::Result<String> {
+    let output = Command::new("tmux").args(arguments).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "tmux {} failed: {}",
+            arguments.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn apple_script_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[test]
+fn ssh_arguments_test() {
+    let mut server = Server::new("web".to_owned(), Some("web.example.com".to_owned()), None, "deploy".to_owned());
+    assert_eq!(ssh_arguments(&server, "web.example.com"), ["--", "deploy@web.example.com"]);
+    server.port = Some(2222);
+    assert_eq!(ssh_arguments(&server, "10.0.0.9"), ["-p", "2222", "--", "deploy@10.0.0.9"]);
+    let hostile = Server::new("web".to_owned(), Some("-oProxyCommand=id".to_owned()), None, "admin".to_owned());
+    assert_eq!(ssh_arguments(&hostile, "-oProxyCommand=id"), ["--", "admin@-oProxyCommand=id"]);
+
+    let launch = Launch::new(Launcher::Inline).with_hooks(Hooks {
+        pre_connect: Some("vpn-up".to_owned()),
+        post_connect: None,
+    });
+    assert_eq!(launch.launcher, Launcher::Inline);
+    assert_eq!(launch.hooks.pre_connect.as_deref(), Some("vpn-up"));
+    assert!(launch.askpass.is_none());
+}
+
+#[test]
+fn tmux_layout_test() {
+    let panes = vec![pane_command(&["rusteze".to_owned(), "connect".to_owned(), "-i".to_owned(), "3".to_owned()])];
+    assert_eq!(panes[0], "exec 'rusteze' 'connect' '-i' '3'");
+    let commands = tmux_layout("@4", &panes, true);
+    assert_eq!(commands.len(), 3);
+    assert_eq!(commands[0], ["split-window", "-t", "@4", "exec 'rusteze' 'connect' '-i' '3'"]);
+    assert_eq!(commands[1], ["select-layout", "-t", "@4", "tiled"]);
+    assert_eq!(commands[2], ["set-window-option", "-t", "@4", "synchronize-panes", "on"]);
+    assert!(tmux_layout("@4", &[], false).is_empty());
+}
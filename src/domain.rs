@@ -1,53 +1,274 @@
-use crate::config::CONFIG_FILE;
-use crate::persistence::{db_action, init_db, CrudAction, Response};
+use crate::config::ConfigFile;
+use crate::paths;
+use crate::persistence::{open_repository, CrudAction, DbError, Repository, Response};
+use crate::connector::Launch;
 use log::{info, warn};
-use mockall::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
 use std::error;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const DEFAULT_DB_NAME: &str = "Server";
+pub const DEFAULT_DB_NAME: &str = "Server";
 pub const DEFAULT_USER: &str = "root";
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Server {
     pub id: Option<i32>,
     pub title: String,
+    #[serde(default)]
+    pub alias: Option<String>,
     pub domain: Option<String>,
     pub ip: Option<String>,
+    /// ssh port, left to ssh when None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
     pub user_name : String,
     pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_connected: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    /// Free-form details such as datacenter, rack or os
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
+    /// Markdown notes, e.g. a runbook
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
 }
 impl Server {
-    pub fn new(title: String, content: String,domain: Option<String>,ip: Option<String>,user_name: String) -> Self {
+    pub fn new(title: String, domain: Option<String>,ip: Option<String>,user_name: String) -> Self {
         Self {
             id: None,
             title,
+            alias: None,
             domain,
             ip,
+            port: None,
             user_name,
             owner: Some(DEFAULT_USER.to_owned()),
+            last_connected: None,
+            deleted_at: None,
+            attributes: BTreeMap::new(),
+            notes: None,
+        }
+    }
+}
+
+/// The checks a server has to pass however it is written, empty optional
+/// fields becoming None
+pub fn validate_server(server: Server) -> Result<Server, ActionErr> {
+    let invalid = |message: &str| Err(ActionErr::build(ActionErrType::InvalidInput(message.to_owned())));
+    let present = |value: Option<String>| value.map(|value| value.trim().to_owned()).filter(|value| !value.is_empty());
+    let server = Server {
+        title: server.title.trim().to_owned(),
+        user_name: server.user_name.trim().to_owned(),
+        alias: present(server.alias),
+        domain: present(server.domain),
+        ip: present(server.ip),
+        ..server
+    };
+    if server.title.is_empty() {
+        invalid("Title is required")
+    } else if server.domain.is_none() && server.ip.is_none() {
+        invalid("Invalid Ip or Domain")
+    } else if server.user_name.is_empty() {
+        invalid("Username is required")
+    } else if [server.domain.as_deref(), server.ip.as_deref(), Some(server.user_name.as_str())]
+        .iter()
+        .flatten()
+        .any(|value| value.starts_with('-') || value.contains(char::is_whitespace))
+    {
+        // ssh would take them for options
+        invalid("Ip, Domain and Username must not start with - nor contain spaces")
+    } else if server
+        .alias
+        .as_ref()
+        .is_some_and(|alias| alias.contains(char::is_whitespace) || alias.parse::<ID>().is_ok())
+    {
+        invalid("Alias must be a single word and not a number")
+    } else {
+        Ok(server)
+    }
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct User {
+    pub id: Option<i32>,
+    pub name: String,
+    pub servers: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Title,
+    Ip,
+    Created,
+    LastConnected,
+}
+impl SortField {
+    pub fn parse(field: &str) -> Option<Self> {
+        match field {
+            "title" => Some(SortField::Title),
+            "ip" => Some(SortField::Ip),
+            "created" => Some(SortField::Created),
+            "last-connected" => Some(SortField::LastConnected),
+            _ => None,
+        }
+    }
+}
+
+/// Paging, ordering and filters applied when listing servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerQuery {
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    pub sort: SortField,
+    pub descending: bool,
+    pub user_name: Option<String>,
+    pub domain_suffix: Option<String>,
+    pub has_ip: Option<bool>,
+    /// Attribute names each server must have, with the value when given
+    pub attributes: Vec<(String, Option<String>)>,
+}
+impl Default for ServerQuery {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            offset: None,
+            sort: SortField::Created,
+            descending: false,
+            user_name: None,
+            domain_suffix: None,
+            has_ip: None,
+            attributes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Outcome of one `doctor` check, with a way to repair it when it failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub check: String,
+    pub severity: Severity,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+impl Finding {
+    pub fn ok(check: &str, message: String) -> Self {
+        Self {
+            check: check.to_owned(),
+            severity: Severity::Ok,
+            message,
+            suggestion: None,
+        }
+    }
+    pub fn warning(check: &str, message: String, suggestion: String) -> Self {
+        Self {
+            check: check.to_owned(),
+            severity: Severity::Warning,
+            message,
+            suggestion: Some(suggestion),
+        }
+    }
+    pub fn error(check: &str, message: String, suggestion: String) -> Self {
+        Self {
+            check: check.to_owned(),
+            severity: Severity::Error,
+            message,
+            suggestion: Some(suggestion),
         }
     }
 }
+
 pub type ID = i64;
 pub enum Action {
     Save(Server),
-    Fetch, //Server : Pagination
+    /// Save servers in one transaction, none of them when one fails
+    SaveAll(Vec<Server>),
+    /// Replace the fields of a server, keeping its attributes not given
+    Update(ID, Server),
+    Fetch(ServerQuery),
     FetchById(ID),
     Delete,
     DeleteById(ID),
-    Connect(ID)
+    FetchTrash,
+    Restore(ID),
+    /// Purge the trash, optionally only entries removed more than the given seconds ago
+    PurgeTrash(Option<i64>),
+    /// Connect with the configured launcher and hooks
+    Connect(ID, Launch),
+    Resolve(String),
+    /// Like Resolve, among the servers in the trash and by id too
+    ResolveTrashed(String),
+    SetAttributes(ID, Vec<(String, String)>),
+    RemoveAttributes(ID, Vec<String>),
+    /// Replace the notes of a server, clearing them with None
+    SetNotes(ID, Option<String>),
+    /// Check the store for damage and inconsistencies
+    Diagnose,
+    /// Rebuild the store to reclaim unused space
+    Vacuum,
+    /// Copy the whole store to a new file
+    Backup(PathBuf),
+    /// Check a backup can be restored, without changing the store
+    VerifyBackup(PathBuf),
+    /// Replace the whole store with a backup
+    RestoreBackup(PathBuf),
+    AddUser(String),
+    FetchUsers,
+    DeleteUser(String),
+    /// Add servers in one transaction, only planning it when dry-run is set
+    Import(Vec<Server>, ConflictPolicy, bool),
+}
+
+/// What `import` does with a server whose host and user are already in the inventory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Upsert,
+    Skip,
+    Fail,
+}
+impl ConflictPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "upsert" => Some(ConflictPolicy::Upsert),
+            "skip" => Some(ConflictPolicy::Skip),
+            "fail" => Some(ConflictPolicy::Fail),
+            _ => None,
+        }
+    }
 }
 
+/// Titles of the servers an import added, updated and skipped
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+// Responses are moved straight to the handler, boxing the server is not worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq, Eq)]
 pub enum ActionResponse {
     Done,
     One(Option<Server>),
     All(Vec<Server>),
+    Users(Vec<User>),
+    Purged(usize),
+    Report(Vec<Finding>),
+    Imported(ImportSummary),
     Empty,
 }
 
@@ -55,6 +276,7 @@ pub enum ActionResponse {
 pub struct ActionErr {
     msg: String,
     error_type: ActionErrType,
+    source: Option<Box<dyn error::Error + Send + Sync>>,
 }
 #[derive(Debug)]
 pub enum ActionErrType {
@@ -62,45 +284,67 @@ pub enum ActionErrType {
     UnableToInitialize,
     TestFailed,
     RecordNotFound,
+    AmbiguousRecord(Vec<Server>),
+    InvalidInput(String),
     ActionFailed(String)
 }
 impl ActionErr {
     pub fn build(server: ActionErrType) -> ActionErr {
-        match server {
-            ActionErrType::InitNotAvailable => ActionErr {
-                msg: "Please initialize application,use help".to_owned(),
-                error_type: ActionErrType::InitNotAvailable,
-            },
-            ActionErrType::UnableToInitialize => ActionErr {
-                msg: "Unable to initizlize application, contact support".to_owned(),
-                error_type: ActionErrType::UnableToInitialize,
-            },
-            ActionErrType::TestFailed => ActionErr {
-                msg: "Database check has failed".to_owned(),
-                error_type: ActionErrType::UnableToInitialize,
-            },
-            ActionErrType::RecordNotFound => ActionErr {
-                msg: "Record Not Found".to_owned(),
-                error_type: ActionErrType::RecordNotFound,
-            },
-            ActionErrType::ActionFailed(message) => ActionErr {
-                msg: message.to_owned(),
-                error_type: ActionErrType::RecordNotFound,
-            },
+        let msg = match &server {
+            ActionErrType::InitNotAvailable => "Please initialize application,use help".to_owned(),
+            ActionErrType::UnableToInitialize => {
+                "Unable to initizlize application, contact support".to_owned()
+            }
+            ActionErrType::TestFailed => "Database check has failed".to_owned(),
+            ActionErrType::RecordNotFound => "Record Not Found".to_owned(),
+            ActionErrType::AmbiguousRecord(candidates) => {
+                format!("{} records match the given input", candidates.len())
+            }
+            ActionErrType::InvalidInput(message) => message.to_owned(),
+            ActionErrType::ActionFailed(message) => message.to_owned(),
+        };
+        ActionErr {
+            msg,
+            error_type: server,
+            source: None,
+        }
+    }
+    /// Attaches the lower level error that caused this one
+    pub fn with_source<E>(mut self, source: E) -> ActionErr
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        self.source = Some(source.into());
+        self
+    }
+    pub fn error_type(&self) -> &ActionErrType {
+        &self.error_type
+    }
+    /// Process exit code reported for this category of error
+    pub fn exit_code(&self) -> i32 {
+        match self.error_type {
+            ActionErrType::ActionFailed(_) => 1,
+            ActionErrType::InvalidInput(_) => 2,
+            ActionErrType::RecordNotFound => 3,
+            ActionErrType::AmbiguousRecord(_) => 4,
+            ActionErrType::InitNotAvailable => 5,
+            ActionErrType::UnableToInitialize => 6,
+            ActionErrType::TestFailed => 7,
         }
     }
 }
 impl fmt::Display for ActionErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "invalid first item to double")
+        write!(f, "{}", self.msg)
     }
 }
 
-// This is important for other errors to wrap this one.
 impl error::Error for ActionErr {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        // Generic error, underlying cause isn't tracked.
-        None
+        match &self.source {
+            Some(source) => Some(source.as_ref()),
+            None => None,
+        }
     }
 }
 
@@ -108,103 +352,571 @@ pub enum Setup {
     Init,
     Test,
 }
-struct ConfigurationArgument {
-    db: bool,
-    set: bool,
+/// Where the inventory is stored, set with the `backend` config key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Sqlite,
+    Json,
+}
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Sqlite => "sqlite",
+            Backend::Json => "json",
+        }
+    }
+    fn extension(&self) -> &'static str {
+        match self {
+            Backend::Sqlite => "store",
+            Backend::Json => "json",
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// How `connect` opens the ssh session, set with the `launcher` config key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Launcher {
+    /// A new macOS Terminal window
+    #[default]
+    Terminal,
+    /// The current terminal, waiting for the session to end
+    Inline,
+}
+impl Launcher {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Launcher::Terminal => "terminal",
+            Launcher::Inline => "inline",
+        }
+    }
+}
+
+/// Level of the log file in the data directory, set with the `log` config key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// No log file
+    #[default]
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+    pub fn filter(&self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+/// How listings are printed, set with the `output` config key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Jsonl,
+    Csv,
+    Yaml,
+    Plain,
+}
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        [
+            OutputFormat::Table,
+            OutputFormat::Json,
+            OutputFormat::Jsonl,
+            OutputFormat::Csv,
+            OutputFormat::Yaml,
+            OutputFormat::Plain,
+        ]
+        .iter()
+        .copied()
+        .find(|format| format.name() == name)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Table => "table",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Plain => "plain",
+        }
+    }
+}
+
+/// Shell commands run around `connect`, with the server in `RUSTEZE_TITLE`,
+/// `RUSTEZE_HOST` and `RUSTEZE_USER`. A failing `pre_connect` cancels the connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Hooks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_connect: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_connect: Option<String>,
+}
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_connect.is_none() && self.post_connect.is_none()
+    }
+}
+
+/// Where the effective value of a setting comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File,
+    Env,
+    Flag,
+}
+impl Origin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Origin::Default => "default",
+            Origin::File => "file",
+            Origin::Env => "env",
+            Origin::Flag => "flag",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Settings {
     pub db: String,
+    pub user: String,
+    pub backend: Backend,
+    pub launcher: Launcher,
+    /// None leaves the format to the terminal
+    pub output: Option<OutputFormat>,
+    pub log: LogLevel,
+    pub hooks: Hooks,
     pub is_saved: bool,
+    /// Origin of every setting not left to its default
+    pub origins: BTreeMap<&'static str, Origin>,
+    /// Problems found in the configuration file that did not prevent loading it
+    pub warnings: Vec<String>,
 }
 
-#[automock]
+#[cfg_attr(test, mockall::automock)]
 impl Settings {
     pub fn system_default() -> Self {
+        Self::create(DEFAULT_DB_NAME.to_string(), DEFAULT_USER.to_string(), Backend::Sqlite, false)
+    }
+    pub fn create(db: String, user: String, backend: Backend, is_saved: bool) -> Self {
         Self {
-            db: DEFAULT_DB_NAME.to_string(),
-            is_saved: false,
+            db,
+            user,
+            backend,
+            launcher: Launcher::default(),
+            output: None,
+            log: LogLevel::default(),
+            hooks: Hooks::default(),
+            is_saved,
+            origins: BTreeMap::new(),
+            warnings: Vec::new(),
         }
     }
-    pub fn create(db: String, is_saved: bool) -> Self {
-        Self { db, is_saved }
+    /// Settings read from a configuration file, every key it sets marked as such
+    pub fn from_config(config: ConfigFile, warnings: Vec<String>) -> Self {
+        let mut settings = Settings::system_default();
+        let mut origins = BTreeMap::new();
+        if let Some(db) = config.db {
+            settings.db = db;
+            origins.insert("db", Origin::File);
+        }
+        if let Some(user) = config.user {
+            settings.user = user;
+            origins.insert("user", Origin::File);
+        }
+        if let Some(backend) = config.backend {
+            settings.backend = backend;
+            origins.insert("backend", Origin::File);
+        }
+        if let Some(launcher) = config.launcher {
+            settings.launcher = launcher;
+            origins.insert("launcher", Origin::File);
+        }
+        if config.output.is_some() {
+            settings.output = config.output;
+            origins.insert("output", Origin::File);
+        }
+        if let Some(log) = config.log {
+            settings.log = log;
+            origins.insert("log", Origin::File);
+        }
+        if config.hooks.pre_connect.is_some() {
+            origins.insert("hooks.pre_connect", Origin::File);
+        }
+        if config.hooks.post_connect.is_some() {
+            origins.insert("hooks.post_connect", Origin::File);
+        }
+        Self {
+            hooks: config.hooks,
+            is_saved: true,
+            origins,
+            warnings,
+            ..settings
+        }
+    }
+    /// The keys to save for these settings
+    pub fn to_config(&self) -> ConfigFile {
+        ConfigFile {
+            db: Some(self.db.to_owned()),
+            user: Some(self.user.to_owned()),
+            backend: Some(self.backend),
+            launcher: Some(self.launcher),
+            output: self.output,
+            log: Some(self.log).filter(|log| *log != LogLevel::Off),
+            hooks: self.hooks.clone(),
+        }
     }
 
+    /// Overrides the configuration file with the `RUSTEZE_*` variables among
+    /// `variables`, the flags overriding both
+    pub fn with_environment(&self, variables: &BTreeMap<String, String>) -> Result<Self, ActionErr> {
+        let mut settings = self.clone();
+        for (name, value) in variables.iter().filter(|(_, value)| !value.trim().is_empty()) {
+            let value = value.trim();
+            let key = match name.as_str() {
+                "RUSTEZE_DB" => {
+                    settings.db = value.to_owned();
+                    "db"
+                }
+                "RUSTEZE_USER" => {
+                    settings.user = value.to_owned();
+                    "user"
+                }
+                "RUSTEZE_BACKEND" => {
+                    settings.backend = parse_variable(name, value)?;
+                    "backend"
+                }
+                "RUSTEZE_LAUNCHER" => {
+                    settings.launcher = parse_variable(name, value)?;
+                    "launcher"
+                }
+                "RUSTEZE_OUTPUT" => {
+                    settings.output = Some(parse_variable(name, value)?);
+                    "output"
+                }
+                "RUSTEZE_LOG" => {
+                    settings.log = parse_variable(name, value)?;
+                    "log"
+                }
+                _ => continue,
+            };
+            settings.origins.insert(key, Origin::Env);
+        }
+        Ok(settings)
+    }
     pub fn update(&self, db: String) -> Self {
+        let mut origins = self.origins.clone();
+        origins.insert("db", Origin::Flag);
         Self {
             db,
-            is_saved: self.is_saved,
+            origins,
+            ..self.clone()
         }
     }
+    pub fn update_user(&self, user: String) -> Self {
+        let mut origins = self.origins.clone();
+        origins.insert("user", Origin::Flag);
+        Self {
+            user,
+            origins,
+            ..self.clone()
+        }
+    }
+    /// Every setting with its effective value and where that value comes from
+    pub fn values(&self) -> Vec<(&'static str, String, Origin)> {
+        let origin = |key| self.origins.get(key).copied().unwrap_or(Origin::Default);
+        let hook = |command: &Option<String>| command.to_owned().unwrap_or_default();
+        vec![
+            ("db", self.db.to_owned(), origin("db")),
+            ("user", self.user.to_owned(), origin("user")),
+            ("backend", self.backend.name().to_owned(), origin("backend")),
+            ("launcher", self.launcher.name().to_owned(), origin("launcher")),
+            (
+                "output",
+                self.output.map_or("auto", |format| format.name()).to_owned(),
+                origin("output"),
+            ),
+            ("log", self.log.name().to_owned(), origin("log")),
+            ("hooks.pre_connect", hook(&self.hooks.pre_connect), origin("hooks.pre_connect")),
+            ("hooks.post_connect", hook(&self.hooks.post_connect), origin("hooks.post_connect")),
+        ]
+    }
     pub fn get_db(&self) -> String {
-        format!("{}.store", self.db.to_owned())
+        paths::data_file(&format!("{}.{}", self.db, self.backend.extension()))
+    }
+    /// Stores of the other backends found next to the configured one
+    pub fn stray_stores(&self) -> Vec<String> {
+        [Backend::Sqlite, Backend::Json]
+            .iter()
+            .filter(|backend| **backend != self.backend)
+            .map(|backend| paths::data_file(&format!("{}.{}", self.db, backend.extension())))
+            .filter(|path| std::path::Path::new(path).exists())
+            .collect()
+    }
+    /// The configured storage backend, opened on the configured db
+    pub fn open_repository(&self) -> Box<dyn Repository> {
+        open_repository(self.backend, self.get_db())
+    }
+    /// Directory of the timestamped backups and automatic snapshots
+    pub fn get_backup_dir(&self) -> String {
+        paths::data_file(&format!("{}.backups", self.db))
+    }
+    /// Encrypted secrets kept next to the db
+    pub fn get_vault(&self) -> String {
+        paths::data_file(&format!("{}.vault", self.db))
+    }
+    pub fn get_launcher(&self) -> Launcher {
+        self.launcher
+    }
+    /// The configured output format, None when left to the terminal
+    pub fn get_output(&self) -> Option<OutputFormat> {
+        self.output
+    }
+    /// Level of the log file, off when there is none
+    pub fn get_log(&self) -> LogLevel {
+        self.log
+    }
+    pub fn get_hooks(&self) -> Hooks {
+        self.hooks.clone()
+    }
+    /// Unknown keys and other problems found while loading the configuration
+    pub fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+    /// Name of the user whose servers every action is scoped to
+    pub fn get_user(&self) -> String {
+        self.user.to_owned()
     }
     pub fn test_setup(&self, db: String) -> Result<ActionResponse, ActionErr> {
-        match db_action(CrudAction::HealthCheck, db) {
-            Response::Success => Ok(ActionResponse::Done),
-            _ => Err(ActionErr::build(ActionErrType::InitNotAvailable)),
+        match open_repository(self.backend, db).execute(CrudAction::Diagnose, &self.user) {
+            Ok(Response::Report(findings)) => match findings
+                .into_iter()
+                .find(|finding| finding.severity == Severity::Error)
+            {
+                Some(failed) => Err(ActionErr::build(ActionErrType::TestFailed)
+                    .with_source(format!("{}, see rusteze doctor", failed.message))),
+                None => Ok(ActionResponse::Done),
+            },
+            Ok(_) => Err(ActionErr::build(ActionErrType::TestFailed)),
+            Err(why @ DbError::NotInitialized(_)) => {
+                Err(ActionErr::build(ActionErrType::InitNotAvailable).with_source(why))
+            }
+            Err(why) => Err(ActionErr::build(ActionErrType::TestFailed).with_source(why)),
         }
     }
     pub fn write_default_config(&self) -> Result<ActionResponse, ActionErr> {
-        let file_options = OpenOptions::new().write(true).open(CONFIG_FILE);
+        let file_options = OpenOptions::new().write(true).truncate(true).open(paths::config_file());
 
         match file_options {
-            Ok(mut file) => match file.write(self.to_string().as_bytes()) {
+            Ok(mut file) => match file.write(saved_content(self).as_bytes()) {
                 Ok(_) => Ok(ActionResponse::Done),
                 Err(why) => {
                     info!("couldn't write to {}", why);
-                    Err(ActionErr::build(ActionErrType::UnableToInitialize))
+                    Err(ActionErr::build(ActionErrType::UnableToInitialize).with_source(why))
                 }
             },
             Err(why) => {
                 info!("couldn't write to {}", why);
-                Err(ActionErr::build(ActionErrType::UnableToInitialize))
+                Err(ActionErr::build(ActionErrType::UnableToInitialize).with_source(why))
             }
         }
     }
+    /// Saves the configuration file with the flags given as the new configuration
     pub fn write_custom_config(&self) -> Result<ActionResponse, ActionErr> {
-        Ok(ActionResponse::Done)
+        let config_file = paths::config_file();
+        let content = saved_content(self);
+        match File::create(&config_file).and_then(|mut file| file.write_all(content.as_bytes())) {
+            Ok(_) => Ok(ActionResponse::Done),
+            Err(why) => {
+                info!("couldn't write to {}", why);
+                Err(ActionErr::build(ActionErrType::ActionFailed(format!(
+                    "Unable to save the configuration to {}",
+                    config_file.display()
+                )))
+                .with_source(why))
+            }
+        }
     }
-    //Server : Improve with Option<Configuration> for load_config()
     pub fn is_config_available(&self) -> bool {
         self.is_saved
     }
     pub fn initalize_db(&self) -> Result<ActionResponse, ActionErr> {
-        let db = format!("{}.store", self.db);
-        match init_db(&db) {
+        match self.open_repository().initialize() {
             Ok(_) => Ok(ActionResponse::Done),
             Err(why) => {
                 warn!("Unable to initiazlize the DB {}", why);
-                Err(ActionErr::build(ActionErrType::UnableToInitialize))
+                Err(ActionErr::build(ActionErrType::UnableToInitialize).with_source(why))
             }
         }
     }
 
+    /// Reads the configuration file. A missing file is `InitNotAvailable`, a
+    /// file that does not parse is `InvalidInput` with the line at fault.
     pub fn load_config() -> Result<Self, ActionErr> {
-        match File::open(CONFIG_FILE) {
-            Ok(config_file) => {
-                let buf_reader = BufReader::new(config_file);
-                let mut db: String = DEFAULT_DB_NAME.to_owned();
-
-                for (_, line) in buf_reader.lines().enumerate() {
-                    let line = line.unwrap();
-                    let split = line.split("=");
-                    let vec = split.collect::<Vec<&str>>();
-
-                    match vec[0] {
-                        "db" => db = vec[1].trim().to_string(),
-                        _ => (),
-                    }
-                }
-                Ok(Settings::create(db, true))
-            }
-            Err(_) => Err(ActionErr::build(ActionErrType::InitNotAvailable)),
+        let path = paths::config_file();
+        let content = fs::read_to_string(&path)
+            .map_err(|why| ActionErr::build(ActionErrType::InitNotAvailable).with_source(why))?;
+        match ConfigFile::parse(&content) {
+            Ok((config, warnings)) => Ok(Settings::from_config(config, warnings)),
+            Err(why) => Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+                "Invalid configuration in {}",
+                path.display()
+            )))
+            .with_source(why)),
         }
     }
+
+    /// The settings of the configuration file, or the defaults when there is
+    /// none, overridden by the `RUSTEZE_*` variables of the environment
+    pub fn load() -> Result<Self, ActionErr> {
+        let settings = match Settings::load_config() {
+            Ok(settings) => {
+                info!("Loaded the configuration from {}", paths::config_file().display());
+                settings
+            }
+            Err(why) if matches!(why.error_type(), ActionErrType::InitNotAvailable) => {
+                info!("No configuration at {}, using the defaults", paths::config_file().display());
+                Settings::system_default()
+            }
+            Err(why) => return Err(why),
+        };
+        let variables = env::vars().filter(|(name, _)| name.starts_with("RUSTEZE_")).collect();
+        settings.with_environment(&variables)
+    }
+}
+/// The configuration file as it is now, with the settings given as flags
+/// over it. The `RUSTEZE_*` variables are left out so that they never
+/// outlive the shell that set them.
+fn saved_config(settings: &Settings, file: ConfigFile) -> ConfigFile {
+    let flag = |key| settings.origins.get(key) == Some(&Origin::Flag);
+    ConfigFile {
+        db: if flag("db") { Some(settings.db.to_owned()) } else { file.db },
+        user: if flag("user") { Some(settings.user.to_owned()) } else { file.user },
+        ..file
+    }
+}
+
+fn saved_content(settings: &Settings) -> String {
+    let file = fs::read_to_string(paths::config_file())
+        .ok()
+        .and_then(|content| ConfigFile::parse(&content).ok())
+        .map(|(config, _)| config)
+        .unwrap_or_default();
+    toml::to_string(&saved_config(settings, file)).unwrap_or_default()
+}
+
+/// Reads a variable holding one of the names of a config enum
+fn parse_variable<T: serde::de::DeserializeOwned>(name: &str, value: &str) -> Result<T, ActionErr> {
+    serde_json::from_value(serde_json::Value::String(value.to_owned())).map_err(|why| {
+        ActionErr::build(ActionErrType::InvalidInput(format!("Invalid value for {}", name))).with_source(why)
+    })
 }
+/// The current unix time in seconds
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
 impl fmt::Display for Settings {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "db={} \n", &self.db.trim().replace(".store", ""))
+        let content = toml::to_string(&self.to_config()).map_err(|_| fmt::Error)?;
+        f.write_str(&content)
     }
 }
+
+#[test]
+fn action_err_build_test() {
+    let failed = ActionErr::build(ActionErrType::ActionFailed("Save action failed".to_owned()))
+        .with_source(std::io::Error::other("disk full"));
+    assert!(matches!(failed.error_type(), ActionErrType::ActionFailed(_)));
+    assert_eq!(failed.to_string(), "Save action failed");
+    assert_eq!(error::Error::source(&failed).unwrap().to_string(), "disk full");
+    assert_eq!(failed.exit_code(), 1);
+
+    let test_failed = ActionErr::build(ActionErrType::TestFailed);
+    assert!(matches!(test_failed.error_type(), ActionErrType::TestFailed));
+    assert_eq!(test_failed.exit_code(), 7);
+}
+
+#[test]
+fn validate_server_test() {
+    let server = |domain: &str, user_name: &str| {
+        validate_server(Server::new("web".to_owned(), Some(domain.to_owned()), None, user_name.to_owned()))
+    };
+    assert_eq!(server(" web.example.com ", "admin").unwrap().domain.as_deref(), Some("web.example.com"));
+    assert!(server("-oProxyCommand=touch /tmp/owned", "admin").is_err());
+    assert!(server("web.example.com", "-oProxyCommand=id").is_err());
+    assert!(server("web example.com", "admin").is_err());
+    let numeric = Server { alias: Some("42".to_owned()), ..Server::new("web".to_owned(), None, Some("10.0.0.1".to_owned()), "admin".to_owned()) };
+    assert!(validate_server(numeric).is_err());
+}
+
+#[test]
+fn settings_origin_test() {
+    let mut loaded = Settings::create("Server".to_owned(), "root".to_owned(), Backend::Sqlite, true);
+    loaded.origins.insert("user", Origin::File);
+    let settings = loaded.update("prod".to_owned());
+    assert_eq!(
+        settings.values()[..4],
+        [
+            ("db", "prod".to_owned(), Origin::Flag),
+            ("user", "root".to_owned(), Origin::File),
+            ("backend", "sqlite".to_owned(), Origin::Default),
+            ("launcher", "terminal".to_owned(), Origin::Default),
+        ]
+    );
+    let variables = BTreeMap::from([
+        ("RUSTEZE_USER".to_owned(), "ci".to_owned()),
+        ("RUSTEZE_OUTPUT".to_owned(), "jsonl".to_owned()),
+        ("RUSTEZE_LOG".to_owned(), "debug".to_owned()),
+        ("RUSTEZE_ASKPASS_SERVER".to_owned(), "1".to_owned()),
+    ]);
+    let overridden = loaded.with_environment(&variables).unwrap().update_user("ops".to_owned());
+    assert_eq!(overridden.output, Some(OutputFormat::Jsonl));
+    assert_eq!(overridden.values()[1], ("user", "ops".to_owned(), Origin::Flag));
+    assert_eq!(overridden.values()[4], ("output", "jsonl".to_owned(), Origin::Env));
+    assert_eq!(overridden.values()[5], ("log", "debug".to_owned(), Origin::Env));
+    let file = ConfigFile {
+        user: Some("root".to_owned()),
+        ..ConfigFile::default()
+    };
+    assert_eq!(
+        toml::to_string(&saved_config(&overridden, file)).unwrap(),
+        "user = \"ops\"\n"
+    );
+    let from_environment = Settings::system_default().with_environment(&variables).unwrap();
+    assert_eq!(saved_config(&from_environment, ConfigFile::default()), ConfigFile::default());
+    let invalid = BTreeMap::from([("RUSTEZE_LAUNCHER".to_owned(), "iterm".to_owned())]);
+    assert!(loaded.with_environment(&invalid).is_err());
+    assert_eq!(
+        settings.to_string(),
+        "db = \"prod\"\nuser = \"root\"\nbackend = \"sqlite\"\nlauncher = \"terminal\"\n"
+    );
+}
@@ -1,215 +1,1298 @@
-use cfg_if::cfg_if;
-use clap::ArgMatches;
-use log::info;
-use std::io::{stdin, stdout, Write};
+use clap::{App, ArgMatches, YamlLoader};
+use log::{debug, warn};
+use std::env;
+use std::fs;
+use std::io::{stdin, stdout, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use crate::config::config_router;
-use crate::domain::{Action, ActionResponse, Server, Setup};
-use crate::service::action_router;
+use rusteze::backup::{rotate, snapshot_path, DEFAULT_KEEP};
+use crate::browser::{self, Session};
+use crate::completion::{self, Candidates, Shell, COMPLETE_COMMAND};
+use rusteze::config::{config_router, set_key, ConfigFile, KEYS};
+use rusteze::connector::{self, Launch};
+use rusteze::domain::{
+    Action, ActionErr, ActionErrType, ActionResponse, ConflictPolicy, Finding, Launcher, Origin, OutputFormat, Server, ServerQuery, Settings, Setup, Severity,
+    SortField, validate_server, ID,
+};
+use crate::output::{describe_age, parse_columns, render, render_one, Column};
+use crate::logger::{self, LOG_FILE_NAME};
+use rusteze::paths;
+use crate::picker;
+use rusteze::service::action_router;
+use rusteze::transfer;
+use rusteze::vault::{has_secret, remove_secret, secret_ids, Askpass, Vault, VaultError};
 
 const DELIMETER: &str = "$";
+/// How long an unlocked vault stays unlocked, in seconds
+const VAULT_SESSION: i64 = 15 * 60;
+/// How long a vault unlocked only to connect answers ssh, in seconds
+const ASKPASS_SESSION: i64 = 60;
+/// Panes group-connect opens without asking
+const GROUP_LIMIT: usize = 12;
 
-cfg_if! {
-    if #[cfg(test)] {
-        use crate::domain::MockSettings as Settings;
-    } else {
-        use crate::domain::Settings;
+/// Settings from the configuration file, the `RUSTEZE_*` variables and the
+/// global flags, each overriding the previous one. A missing file leaves the
+/// defaults, a file that does not parse is an error.
+pub fn handle_config_argument(matches: &ArgMatches) -> Result<Settings, ActionErr> {
+    let locations = paths::init(matches.value_of("home"));
+    match env::current_dir().and_then(|cwd| paths::migrate_legacy(&cwd, locations)) {
+        Ok(moved) => {
+            for file in moved {
+                let name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                eprintln!("Moved {} to {}", name, file.display());
+            }
+        }
+        Err(why) => warn!("Unable to move the files of the current directory: {}", why),
+    }
+    let base_settings = Settings::load()?;
+    for warning in base_settings.get_warnings() {
+        eprintln!("Warning: {}: {}", paths::config_file().display(), warning);
+    }
+    // value_of rather than is_present, which also holds for the `db` subcommand
+    let settings = match matches.value_of("db") {
+        Some(db) => base_settings.update(db.trim().to_lowercase()),
+        None => base_settings,
+    };
+    let settings = match matches.value_of("as") {
+        Some(user) => settings.update_user(user.trim().to_owned()),
+        None => settings,
+    };
+    let log_file = paths::data_file(LOG_FILE_NAME);
+    if let Err(why) = logger::open_file(settings.get_log().filter(), Path::new(&log_file)) {
+        warn!("Unable to open the log file {}: {}", log_file, why);
+    }
+    for (key, value, origin) in settings.values() {
+        debug!("{} = {:?} from {}", key, value, origin.name());
+    }
+    Ok(settings)
+}
+
+/// Saves the flags passed along with `--set` as the new defaults
+pub fn handle_set(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if matches.is_present("set") {
+        if !settings.is_config_available() {
+            return Err(ActionErr::build(ActionErrType::InitNotAvailable));
+        }
+        settings.write_custom_config()?;
+        println!("Settings saved");
     }
+    Ok(())
 }
 
-pub fn handle_config_argument(matches: &ArgMatches) -> Settings {
-    let base_settings = match Settings::load_config() {
-        Ok(settings) => settings,
-        Err(why) => {
-            info!("Unable to load configuraiton, setting default : {}", why);
-            Settings::system_default()
+pub fn handle_config(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("config") {
+        if matches.subcommand_matches("list").is_some() {
+            for (key, value, origin) in settings.values() {
+                println!("{:<18} {:<24} ({})", key, value, origin.name());
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("get") {
+            let key = matches.value_of("key").unwrap_or_default();
+            match settings.values().into_iter().find(|(name, _, _)| *name == key) {
+                Some((_, value, _)) => println!("{}", value),
+                None => {
+                    return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+                        "Unknown key {}, expected one of {}",
+                        key,
+                        KEYS.join(", ")
+                    ))))
+                }
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("set") {
+            if !settings.is_config_available() {
+                return Err(ActionErr::build(ActionErrType::InitNotAvailable));
+            }
+            let key = matches.value_of("key").unwrap_or_default();
+            let path = paths::config_file();
+            let content = fs::read_to_string(&path).map_err(|why| access_failed(&path, why))?;
+            let updated = set_key(&content, key, matches.value_of("value").unwrap_or_default())?;
+            fs::write(&path, updated).map_err(|why| access_failed(&path, why))?;
+            println!("Saved {} in {}", key, path.display());
+        }
+        if matches.subcommand_matches("edit").is_some() {
+            if !settings.is_config_available() {
+                return Err(ActionErr::build(ActionErrType::InitNotAvailable));
+            }
+            edit_config()?;
+        }
+    }
+    Ok(())
+}
+
+/// Edits a copy of the configuration and only replaces the file once the copy
+/// loads, offering to edit it again when it does not
+fn edit_config() -> Result<(), ActionErr> {
+    let path = paths::config_file();
+    let original = fs::read_to_string(&path).map_err(|why| access_failed(&path, why))?;
+    let draft = path.with_extension("edit.toml");
+    fs::write(&draft, &original).map_err(|why| access_failed(&draft, why))?;
+    let result = loop {
+        if let Err(why) = run_editor(&draft) {
+            break Err(why);
+        }
+        let content = match fs::read_to_string(&draft) {
+            Ok(content) => content,
+            Err(why) => break Err(access_failed(&draft, why)),
+        };
+        match ConfigFile::parse(&content) {
+            Ok((_, warnings)) => {
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                if content == original {
+                    println!("No changes");
+                    break Ok(());
+                }
+                break fs::rename(&draft, &path)
+                    .map(|_| println!("Saved {}", path.display()))
+                    .map_err(|why| access_failed(&path, why));
+            }
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                if !edit_again_confirmation() {
+                    break Err(ActionErr::build(ActionErrType::InvalidInput(
+                        "The configuration was left unchanged".to_owned(),
+                    )));
+                }
+            }
         }
     };
-    if matches.is_present("db") {
-        let db = matches
-            .value_of("db")
-            .unwrap_or(base_settings.get_db().as_str())
-            .trim()
-            .to_lowercase();
-        base_settings.update(db)
+    let _ = fs::remove_file(&draft);
+    result
+}
+
+/// Runs the editor of the user on `file`, through the shell so that
+/// `EDITOR="code --wait"` works
+fn run_editor(file: &Path) -> Result<(), ActionErr> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(file)
+        .status()
+        .map_err(|why| invalid_input("Unable to start the editor", why))?;
+    if status.success() {
+        Ok(())
     } else {
-        base_settings
+        Err(ActionErr::build(ActionErrType::ActionFailed(format!("{} exited with {}", editor, status))))
     }
 }
 
-pub fn handle_init(matches: &ArgMatches, settings: &Settings) {
+fn access_failed(path: &Path, why: std::io::Error) -> ActionErr {
+    ActionErr::build(ActionErrType::ActionFailed(format!("Unable to access {}", path.display()))).with_source(why)
+}
+
+pub fn handle_init(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
     if matches.is_present("init") {
-        match config_router(settings, Setup::Init) {
-            Ok(_) => println!("Initialization completed successful"),
-            Err(why) => println!("Initialization has failed - Reason : {}", why),
-        }
+        config_router(settings, Setup::Init)?;
+        println!("Initialization completed successful");
     }
+    Ok(())
 }
 
-pub fn handle_test(matches: &ArgMatches, settings: &Settings) {
+pub fn handle_test(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
     if matches.is_present("test") {
-        match config_router(settings, Setup::Test) {
-            Ok(_) => println!("Test completed successful"),
-            Err(_) => println!("Test has failed, Please initalize"),
-        }
+        config_router(settings, Setup::Test)?;
+        println!("Test completed successful");
     }
+    Ok(())
 }
 
-pub fn handle_connect(matches: &ArgMatches, settings: &Settings) {
+pub fn handle_connect(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
     if let Some(matches) = matches.subcommand_matches("connect") {
-        if let Some(id) = matches.value_of("input").map(|id| id.trim().parse::<i64>()) {
-            match id {
-                Ok(record_id) => {
-                    if let Ok(_) = action_router(settings, Action::Connect(record_id)) {
-                        println!("Terminal connected")
-                    } else {
-                        println!("Connect terminal failed")
+        let record_id = match matches.value_of("input") {
+            Some(input) => select_server(settings, input)?,
+            None => match pick_server(settings)? {
+                Some(record_id) => record_id,
+                None => return Ok(()),
+            },
+        };
+        connect_server(settings, record_id)?;
+        if settings.get_launcher() == Launcher::Terminal {
+            println!("Terminal connected");
+        }
+    }
+    Ok(())
+}
+
+fn connect_server(settings: &Settings, record_id: ID) -> Result<(), ActionErr> {
+    let mut launch = Launch::new(settings.get_launcher()).with_hooks(settings.get_hooks());
+    let mut hand_off = false;
+    if let Some((askpass, temporary)) = askpass_for(settings, record_id)? {
+        launch = launch.with_askpass(askpass);
+        hand_off = temporary;
+    }
+    let connected = action_router(settings, Action::Connect(record_id, launch));
+    // ssh in a new window may still ask, that session runs out on its own
+    if hand_off && settings.get_launcher() == Launcher::Inline {
+        if let Err(why) = Vault::forget(&settings.get_vault()) {
+            warn!("Unable to lock the vault again {}", why);
+        }
+    }
+    connected.map(|_| ())
+}
+
+/// Opens every server with the given attributes in a tmux pane of its own,
+/// asking first when there are more than the limit
+pub fn handle_group_connect(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(group) = matches.subcommand_matches("group-connect") {
+        let filters = group.values_of("attr").map(|filters| filters.collect::<Vec<_>>()).unwrap_or_default();
+        let query = ServerQuery {
+            sort: SortField::Title,
+            attributes: filters.iter().map(|filter| parse_attribute_filter(filter)).collect(),
+            ..ServerQuery::default()
+        };
+        let servers = match action_router(settings, Action::Fetch(query))? {
+            ActionResponse::All(servers) if !servers.is_empty() => servers,
+            _ => return Err(ActionErr::build(ActionErrType::RecordNotFound)),
+        };
+        let limit = match group.value_of("max") {
+            Some(value) => value.trim().parse::<usize>().map_err(|why| {
+                ActionErr::build(ActionErrType::InvalidInput("Not a valid max".to_owned())).with_source(why)
+            })?,
+            None => GROUP_LIMIT,
+        };
+        if servers.len() > limit && !group.is_present("yes") {
+            if !stdin().is_terminal() {
+                return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+                    "{} servers match, pass --yes to open more than {}",
+                    servers.len(),
+                    limit
+                ))));
+            }
+            if !open_confirmation(servers.len()) {
+                return Ok(());
+            }
+        }
+        let commands = servers
+            .iter()
+            .filter_map(|server| server.id)
+            .map(|id| group_pane_command(matches, settings, id.into()))
+            .collect::<Result<Vec<_>, _>>()?;
+        connector::open_tmux_window(&filters.join(","), &commands, group.is_present("sync")).map_err(|why| {
+            ActionErr::build(ActionErrType::ActionFailed("Unable to open the tmux panes".to_owned())).with_source(why)
+        })?;
+    }
+    Ok(())
+}
+
+/// The command a pane runs to connect to `record_id` inline, with the
+/// locations, variables and flags of this run
+fn group_pane_command(matches: &ArgMatches, settings: &Settings, record_id: ID) -> Result<Vec<String>, ActionErr> {
+    let executable = env::current_exe().map_err(|why| {
+        ActionErr::build(ActionErrType::ActionFailed("Unable to locate rusteze".to_owned())).with_source(why)
+    })?;
+    let mut command = vec!["env".to_owned(), "RUSTEZE_LAUNCHER=inline".to_owned()];
+    command.extend(
+        env::vars()
+            .filter(|(name, _)| {
+                (name.starts_with("RUSTEZE_") && name != "RUSTEZE_LAUNCHER")
+                    || name == "XDG_CONFIG_HOME"
+                    || name == "XDG_DATA_HOME"
+            })
+            .map(|(name, value)| format!("{}={}", name, value)),
+    );
+    command.push(executable.to_string_lossy().into_owned());
+    if let Some(home) = matches.value_of("home") {
+        let home = fs::canonicalize(home).unwrap_or_else(|_| PathBuf::from(home));
+        command.extend(["--home".to_owned(), home.to_string_lossy().into_owned()]);
+    }
+    for (key, value, origin) in settings.values() {
+        match (key, origin) {
+            ("db", Origin::Flag) => command.extend(["--db".to_owned(), value]),
+            ("user", Origin::Flag) => command.extend(["--as".to_owned(), value]),
+            _ => (),
+        }
+    }
+    command.extend(["connect".to_owned(), "-i".to_owned(), record_id.to_string()]);
+    Ok(command)
+}
+
+pub fn handle_tui(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if matches.subcommand_matches("tui").is_some() {
+        if !stdin().is_terminal() || !stdout().is_terminal() {
+            return Err(ActionErr::build(ActionErrType::InvalidInput(
+                "The terminal interface needs a terminal".to_owned(),
+            )));
+        }
+        browser::browse(&SettingsSession(settings), describe_server, describe_details)?;
+    }
+    Ok(())
+}
+
+/// The browser acting through the service layer like the commands do
+struct SettingsSession<'a>(&'a Settings);
+
+impl Session for SettingsSession<'_> {
+    fn route(&self, action: Action) -> Result<ActionResponse, ActionErr> {
+        action_router(self.0, action)
+    }
+
+    fn validate(&self, server: Server) -> Result<Server, ActionErr> {
+        validate_server(server)
+    }
+
+    fn connect(&self, id: ID) -> Result<(), ActionErr> {
+        connect_server(self.0, id)
+    }
+}
+
+pub fn handle_add(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("add") {
+        if matches.is_present("from-stdin") {
+            let servers = read_server_lines(stdin().lock())?;
+            let saved = servers.len();
+            action_router(settings, Action::SaveAll(servers))?;
+            println!("Saved {} server(s)", saved);
+            return Ok(());
+        }
+        let server = if ADD_FLAGS.iter().any(|flag| matches.is_present(flag)) {
+            server_from_flags(matches)?
+        } else {
+            loop {
+                match read_add_input() {
+                    Ok(server) => break server,
+                    Err(why) if why.kind() == std::io::ErrorKind::InvalidData => {
+                        retry_prompt(why.to_string())
+                    }
+                    Err(why) => {
+                        return Err(ActionErr::build(ActionErrType::InvalidInput(
+                            "Unable to read the server details".to_owned(),
+                        ))
+                        .with_source(why))
                     }
                 }
-                Err(_) => println!("Save has failed, Please use test command"),
             }
+        };
+        action_router(settings, Action::Save(server))?;
+        println!("Saved successful");
+    }
+    Ok(())
+}
+pub fn handle_list(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("list") {
+        let (format, columns) = output_options(matches, settings)?;
+        if let Some(input) = matches.value_of("input") {
+            let record_id = select_server(settings, input)?;
+            let record = fetch_server(settings, record_id)?;
+            print!("{}", render_one(record, format, columns.as_deref())?);
+        } else {
+            let query = read_list_query(matches)?;
+            match action_router(settings, Action::Fetch(query))? {
+                ActionResponse::All(servers) => print!("{}", render(&servers, format, columns.as_deref())?),
+                _ => eprintln!("Records not found"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_list_query(matches: &ArgMatches) -> Result<ServerQuery, ActionErr> {
+    fn number(matches: &ArgMatches, name: &str) -> Result<Option<u32>, ActionErr> {
+        match matches.value_of(name) {
+            Some(value) => match value.trim().parse::<u32>() {
+                Ok(number) => Ok(Some(number)),
+                Err(why) => Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+                    "Not a valid {}",
+                    name
+                )))
+                .with_source(why)),
+            },
+            None => Ok(None),
         }
     }
+    let limit = number(matches, "limit")?;
+    let offset = number(matches, "offset")?;
+    let has_ip = if matches.is_present("has-ip") {
+        Some(true)
+    } else if matches.is_present("no-ip") {
+        Some(false)
+    } else {
+        None
+    };
+    Ok(ServerQuery {
+        limit,
+        offset,
+        sort: matches
+            .value_of("sort")
+            .and_then(SortField::parse)
+            .unwrap_or(SortField::Created),
+        descending: matches.is_present("desc"),
+        user_name: matches.value_of("user").map(|user| user.trim().to_owned()),
+        domain_suffix: matches.value_of("domain").map(|domain| domain.trim().to_owned()),
+        has_ip,
+        attributes: matches
+            .values_of("attr")
+            .map(|filters| filters.map(parse_attribute_filter).collect())
+            .unwrap_or_default(),
+    })
+}
+
+/// `key=value` matches servers with that value, a bare `key` any server having the attribute
+fn parse_attribute_filter(filter: &str) -> (String, Option<String>) {
+    match filter.split_once('=') {
+        Some((name, value)) => (name.trim().to_owned(), Some(value.trim().to_owned())),
+        None => (filter.trim().to_owned(), None),
+    }
 }
 
-pub fn handle_add(matches: &ArgMatches, settings: &Settings) {
-    if let Some(_) = matches.subcommand_matches("add") {
-        let mut input = read_add_input();
-       while let Err(why) = &input {
-           println!("{:?}",why);
-            retry_prompt("Ip or domain is needed".to_owned());
-            input = read_add_input();            
+pub fn handle_show(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("show") {
+        let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+        let record = fetch_server(settings, record_id)?;
+        match output_options(matches, settings)? {
+            (OutputFormat::Table, None) => print!("{}", describe_details(&record)),
+            (format, columns) => print!("{}", render_one(record, format, columns.as_deref())?),
         }
-        if let Ok(server) = input {
-            match action_router(&settings, Action::Save(server)) {
-                Ok(_) => println!("Saved successful"),
-                Err(_) => println!("Save has failed, Please use test command"),
+    }
+    Ok(())
+}
+
+/// The format from `--format`, the configuration, or a table on a terminal
+/// and JSON lines otherwise, along with the columns from `--columns`
+fn output_options(matches: &ArgMatches, settings: &Settings) -> Result<(OutputFormat, Option<Vec<Column>>), ActionErr> {
+    let format = match matches.value_of("format").and_then(OutputFormat::parse) {
+        Some(format) => format,
+        None => settings.get_output().unwrap_or(if stdout().is_terminal() {
+            OutputFormat::Table
+        } else {
+            OutputFormat::Jsonl
+        }),
+    };
+    let columns = matches.value_of("columns").map(parse_columns).transpose()?;
+    Ok((format, columns))
+}
+
+pub fn handle_attr(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("attr") {
+        if let Some(matches) = matches.subcommand_matches("set") {
+            let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+            let attributes = matches
+                .values_of("attributes")
+                .map(|pairs| pairs.map(parse_attribute).collect::<Result<Vec<_>, _>>())
+                .unwrap_or_else(|| Ok(Vec::new()))?;
+            action_router(settings, Action::SetAttributes(record_id, attributes))?;
+            println!("Attributes saved for id {}", record_id);
+        } else if let Some(matches) = matches.subcommand_matches("rm") {
+            let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+            let names = matches
+                .values_of("names")
+                .map(|names| names.map(|name| name.trim().to_owned()).collect())
+                .unwrap_or_default();
+            action_router(settings, Action::RemoveAttributes(record_id, names))?;
+            println!("Attributes removed for id {}", record_id);
+        }
+    }
+    Ok(())
+}
+
+fn parse_attribute(pair: &str) -> Result<(String, String), ActionErr> {
+    match pair.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+            "Attribute {} is not in the key=value form",
+            pair
+        )))),
+    }
+}
+
+pub fn handle_note(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("note") {
+        let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+        let notes = if matches.is_present("clear") {
+            None
+        } else {
+            if stdin().is_terminal() {
+                eprintln!("Enter the markdown notes, end with Ctrl-D");
             }
-        }        
+            let mut notes = String::new();
+            stdin()
+                .read_to_string(&mut notes)
+                .map_err(|why| invalid_input("Unable to read the notes", why))?;
+            Some(notes.trim_end().to_owned()).filter(|notes| !notes.is_empty())
+        };
+        action_router(settings, Action::SetNotes(record_id, notes))?;
+        println!("Notes saved for id {}", record_id);
     }
+    Ok(())
 }
-pub fn handle_list(matches: &ArgMatches, settings: &Settings) {
-    if let Some(matches) = matches.subcommand_matches("list") {
-        if let Some(id) = matches.value_of("input").map(|id| id.trim().parse::<i64>()) {
-            match id {
-                Ok(record_id) => {
-                    if let Ok(response) = action_router(&settings, Action::FetchById(record_id)) {
-                        match response {
-                            ActionResponse::One(server) => {
-                                if let Some(record) = server {
-                                    let serialized_server = serde_json::to_string(&record).unwrap();
-                                    println!("{}", serialized_server);
-                                } else {
-                                    println!("Record not found")
-                                }
-                            }
-                            _ => println!("Record not found"),
-                        }
-                    } else {
-                        println!("Record not found")
+
+pub fn handle_user(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("user") {
+        if let Some(matches) = matches.subcommand_matches("add") {
+            let name = matches.value_of("name").unwrap_or_default().trim();
+            action_router(settings, Action::AddUser(name.to_owned()))?;
+            println!("User {} added", name);
+        } else if matches.subcommand_matches("list").is_some() {
+            match action_router(settings, Action::FetchUsers)? {
+                ActionResponse::Users(users) => {
+                    for user in users {
+                        let serialized_user = serde_json::to_string(&user).unwrap();
+                        println!("{}", serialized_user);
                     }
                 }
-                Err(_) => println!("Not a valid integer"),
+                _ => eprintln!("Records not found"),
+            }
+        } else if let Some(matches) = matches.subcommand_matches("remove") {
+            let name = matches.value_of("name").unwrap_or_default().trim();
+            if remove_confirmation(&format!("the user {}", name))? {
+                action_router(settings, Action::DeleteUser(name.to_owned()))?;
+                println!("User {} removed", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_secret(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("secret") {
+        let vault_path = settings.get_vault();
+        if let Some(matches) = matches.subcommand_matches("set") {
+            let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+            let record = fetch_server(settings, record_id)?;
+            let mut vault = unlock_vault(settings)?;
+            let secret = read_hidden(&format!("Secret for {}", record.title))
+                .map_err(|why| invalid_input("Unable to read the secret", why))?;
+            if secret.is_empty() {
+                return Err(ActionErr::build(ActionErrType::InvalidInput(
+                    "Secret must not be empty".to_owned(),
+                )));
+            }
+            vault.set(record_id, &secret).map_err(vault_failed)?;
+            println!("Secret saved for {} (id : {})", record.title, record_id);
+        } else if let Some(matches) = matches.subcommand_matches("get") {
+            let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+            if !has_secret(&vault_path, record_id).map_err(vault_failed)? {
+                return Err(ActionErr::build(ActionErrType::RecordNotFound));
+            }
+            match unlock_vault(settings)?.get(record_id).map_err(vault_failed)? {
+                Some(secret) => println!("{}", secret),
+                None => return Err(ActionErr::build(ActionErrType::RecordNotFound)),
+            }
+        } else if let Some(matches) = matches.subcommand_matches("rm") {
+            let record_id = select_server(settings, matches.value_of("input").unwrap_or_default())?;
+            let removed = Vault::exists(&vault_path)
+                && remove_secret(&vault_path, record_id).map_err(vault_failed)?;
+            if !removed {
+                return Err(ActionErr::build(ActionErrType::RecordNotFound));
+            }
+            println!("Secret removed for id {}", record_id);
+        } else if let Some(matches) = matches.subcommand_matches("unlock") {
+            let ttl = match matches.value_of("ttl") {
+                Some(ttl) => parse_age(ttl).ok_or_else(|| {
+                    ActionErr::build(ActionErrType::InvalidInput(
+                        "Not a valid duration, use a number followed by s, m, h, d or w".to_owned(),
+                    ))
+                })?,
+                None => VAULT_SESSION,
+            };
+            unlock_vault(settings)?.remember(ttl).map_err(vault_failed)?;
+            println!("Vault unlocked for {} minute(s)", ttl / 60);
+        } else if matches.subcommand_matches("lock").is_some() {
+            if Vault::exists(&vault_path) {
+                Vault::forget(&vault_path).map_err(vault_failed)?;
+            }
+            println!("Vault locked");
+        }
+    }
+    Ok(())
+}
+
+/// Unlocks the vault from the session cache or by asking for the master
+/// passphrase, creating the vault on first use
+fn unlock_vault(settings: &Settings) -> Result<Vault, ActionErr> {
+    let path = settings.get_vault();
+    if Vault::exists(&path) {
+        if let Some(vault) = Vault::from_session(&path).map_err(vault_failed)? {
+            return Ok(vault);
+        }
+    }
+    let unreadable = |why| invalid_input("Unable to read the vault passphrase", why);
+    let vault = if Vault::exists(&path) {
+        let passphrase = read_hidden("Vault passphrase").map_err(unreadable)?;
+        Vault::unlock(&path, &passphrase).map_err(vault_failed)?
+    } else {
+        eprintln!("Creating the vault {}", path);
+        let passphrase = read_hidden("New vault passphrase").map_err(unreadable)?;
+        if passphrase.is_empty() || read_hidden("Repeat the passphrase").map_err(unreadable)? != passphrase {
+            return Err(ActionErr::build(ActionErrType::InvalidInput(
+                "Passphrases are empty or do not match".to_owned(),
+            )));
+        }
+        Vault::create(&path, &passphrase).map_err(vault_failed)?
+    };
+    Ok(vault)
+}
+
+/// Sets up ssh to ask rusteze for the password when the server has a secret.
+/// Without a session from `secret unlock`, the vault is unlocked only for the
+/// hand-off, which is true then.
+fn askpass_for(settings: &Settings, record_id: ID) -> Result<Option<(Askpass, bool)>, ActionErr> {
+    let vault_path = settings.get_vault();
+    if !has_secret(&vault_path, record_id).map_err(vault_failed)? {
+        return Ok(None);
+    }
+    let temporary = Vault::from_session(&vault_path).map_err(vault_failed)?.is_none();
+    if temporary {
+        unlock_vault(settings)?.remember(ASKPASS_SESSION).map_err(vault_failed)?;
+    }
+    Askpass::new(&vault_path, record_id)
+        .map(|askpass| Some((askpass, temporary)))
+        .map_err(vault_failed)
+}
+
+fn vault_failed(why: VaultError) -> ActionErr {
+    match why {
+        VaultError::WrongPassphrase => {
+            ActionErr::build(ActionErrType::InvalidInput(why.to_string()))
+        }
+        _ => ActionErr::build(ActionErrType::ActionFailed("Vault action failed".to_owned()))
+            .with_source(why),
+    }
+}
+
+fn invalid_input(message: &str, why: std::io::Error) -> ActionErr {
+    ActionErr::build(ActionErrType::InvalidInput(message.to_owned())).with_source(why)
+}
+
+/// Reads a line without echoing it on a terminal, or plainly from piped input
+fn read_hidden(msg: &str) -> Result<String, std::io::Error> {
+    if stdin().is_terminal() {
+        return rpassword::prompt_password(format!("{} {} ", msg, DELIMETER));
+    }
+    let mut input = String::new();
+    if stdin().read_line(&mut input)? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Input ended before the value was entered",
+        ));
+    }
+    clean_input(&mut input);
+    Ok(input)
+}
+
+pub fn handle_remove(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("remove") {
+        if let Some(input) = matches.value_of("input") {
+            let record_id = select_server(settings, input)?;
+            let record = fetch_server(settings, record_id)?;
+            let message = format!("{} (id : {})", record.title, record_id);
+            if remove_confirmation(&message)? {
+                action_router(settings, Action::DeleteById(record_id))?;
+                println!(
+                    "Successfuly removed a record id {}, use restore to undo",
+                    record_id
+                );
+            }
+        } else if remove_confirmation("all records")? {
+            let snapshot = take_snapshot(settings, &settings.get_backup_dir(), Some("remove"), DEFAULT_KEEP)?;
+            eprintln!("Snapshot saved to {}", snapshot.display());
+            action_router(settings, Action::Delete)?;
+            println!("Remove all successful, records are kept in the trash");
+        }
+    }
+    Ok(())
+}
+pub fn handle_export(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("export") {
+        let file = matches.value_of("file");
+        let format = transfer_format(matches.value_of("format"), file);
+        let servers = match action_router(settings, Action::Fetch(ServerQuery::default()))? {
+            ActionResponse::All(servers) => servers,
+            _ => Vec::new(),
+        };
+        let content = transfer::export(&servers, format)?;
+        match file {
+            Some(file) => {
+                fs::write(file, content).map_err(|why| access_failed(Path::new(file), why))?;
+                eprintln!("Exported {} server(s) to {}", servers.len(), file);
             }
+            None => print!("{}", content),
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_import(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let file = matches.value_of("file").unwrap_or("-");
+        let mut content = String::new();
+        if file == "-" {
+            stdin()
+                .read_to_string(&mut content)
+                .map_err(|why| invalid_input("Unable to read the servers", why))?;
         } else {
-            if let Ok(response) = action_router(&settings, Action::Fetch) {
-                match response {
-                    ActionResponse::All(servers) => {
-                        for server in servers {
-                            let serialized_server = serde_json::to_string(&server).unwrap();
-                            println!("{}", serialized_server);
-                        }
-                    }
-                    _ => println!("Records not found"),
+            content = fs::read_to_string(file).map_err(|why| access_failed(Path::new(file), why))?;
+        }
+        let format = transfer_format(matches.value_of("format"), Some(file).filter(|file| *file != "-"));
+        let mut servers = Vec::new();
+        let mut invalid = 0;
+        for (index, server) in transfer::parse(&content, format)?.into_iter().enumerate() {
+            match validate_server(server) {
+                Ok(server) => servers.push(server),
+                Err(why) => {
+                    eprintln!("Server {}: {}", index + 1, why);
+                    invalid += 1;
+                }
+            }
+        }
+        if invalid > 0 {
+            return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+                "{} invalid server(s), nothing was imported",
+                invalid
+            ))));
+        }
+        let policy = matches
+            .value_of("on-conflict")
+            .and_then(ConflictPolicy::parse)
+            .unwrap_or(ConflictPolicy::Fail);
+        let dry_run = matches.is_present("dry-run");
+        if !dry_run {
+            let snapshot = take_snapshot(settings, &settings.get_backup_dir(), Some("import"), DEFAULT_KEEP)?;
+            eprintln!("Snapshot saved to {}", snapshot.display());
+        }
+        if let ActionResponse::Imported(summary) = action_router(settings, Action::Import(servers, policy, dry_run))? {
+            for (mark, titles) in [("+", &summary.added), ("~", &summary.updated), ("=", &summary.skipped)].iter() {
+                for title in titles.iter() {
+                    println!("{} {}", mark, title);
                 }
+            }
+            println!(
+                "{} {} added, {} updated, {} skipped",
+                if dry_run { "Dry run:" } else { "Imported:" },
+                summary.added.len(),
+                summary.updated.len(),
+                summary.skipped.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The format from `--format`, otherwise csv for a .csv file and json for anything else
+fn transfer_format(flag: Option<&str>, file: Option<&str>) -> OutputFormat {
+    match flag.and_then(OutputFormat::parse) {
+        Some(format) => format,
+        None if file.is_some_and(|file| file.to_lowercase().ends_with(".csv")) => OutputFormat::Csv,
+        None => OutputFormat::Json,
+    }
+}
+
+/// The app of cli.yml, with what its yaml can not express
+pub fn command_line() -> App<'static> {
+    // The app borrows the yaml for as long as the process runs
+    let documents = Box::leak(Box::new(YamlLoader::load_from_str(include_str!("cli.yml")).expect("cli.yml is valid")));
+    App::from(&documents[0]).mut_arg("verbose", |arg| arg.multiple_occurrences(true))
+}
+
+pub fn handle_completions(matches: &ArgMatches, _settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        if let Some(shell) = matches.value_of("shell").and_then(Shell::parse) {
+            print!("{}", completion::script(&command_line(), shell));
+        }
+    }
+    Ok(())
+}
+
+/// Prints the candidates the completion scripts offer, nothing at all when
+/// the inventory can not be read so that a shell never shows an error
+pub fn handle_complete(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches(COMPLETE_COMMAND) {
+        if let Some(kind) = matches.value_of("kind").and_then(Candidates::parse) {
+            if let Ok(ActionResponse::All(servers)) = action_router(settings, Action::Fetch(ServerQuery::default())) {
+                for candidate in completion::candidates(&servers, kind) {
+                    println!("{}", candidate);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_db(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("db") {
+        if let Some(matches) = matches.subcommand_matches("backup") {
+            let keep = match matches.value_of("keep") {
+                Some(keep) => keep.trim().parse::<usize>().map_err(|_| {
+                    ActionErr::build(ActionErrType::InvalidInput(
+                        "keep must be a positive integer".to_owned(),
+                    ))
+                })?,
+                None => DEFAULT_KEEP,
+            };
+            let target = match matches.value_of("path") {
+                Some(path) if !Path::new(path).is_dir() => {
+                    action_router(settings, Action::Backup(PathBuf::from(path)))?;
+                    PathBuf::from(path)
+                }
+                Some(directory) => take_snapshot(settings, directory, None, keep)?,
+                None => take_snapshot(settings, &settings.get_backup_dir(), None, keep)?,
+            };
+            println!("Backup written to {}", target.display());
+        } else if let Some(matches) = matches.subcommand_matches("restore") {
+            let source = PathBuf::from(matches.value_of("file").unwrap_or_default());
+            action_router(settings, Action::VerifyBackup(source.clone()))?;
+            let message = format!("all current records, replacing them with {}", source.display());
+            if remove_confirmation(&message)? {
+                let snapshot = take_snapshot(settings, &settings.get_backup_dir(), Some("restore"), DEFAULT_KEEP)?;
+                eprintln!("Snapshot saved to {}", snapshot.display());
+                action_router(settings, Action::RestoreBackup(source.clone()))?;
+                println!("Restored from {}", source.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Backs the db up to a timestamped file in `directory`, keeping the newest
+/// `keep` backups there. With a reason, the snapshot is an automatic one,
+/// rotated apart from the others.
+fn take_snapshot(settings: &Settings, directory: &str, reason: Option<&str>, keep: usize) -> Result<PathBuf, ActionErr> {
+    let directory = Path::new(directory);
+    fs::create_dir_all(directory).map_err(|why| {
+        ActionErr::build(ActionErrType::ActionFailed(format!(
+            "Unable to create the backup directory {}",
+            directory.display()
+        )))
+        .with_source(why)
+    })?;
+    let db = settings.get_db();
+    let target = snapshot_path(directory, Path::new(&db), reason);
+    action_router(settings, Action::Backup(target.clone()))?;
+    if let Err(why) = rotate(directory, Path::new(&db), reason.is_some(), keep.max(1)) {
+        warn!("Unable to remove old backups in {} {}", directory.display(), why);
+    }
+    Ok(target)
+}
+
+pub fn handle_doctor(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("doctor") {
+        let mut findings = config_findings(settings);
+        if settings.is_config_available() {
+            match action_router(settings, Action::Diagnose) {
+                Ok(ActionResponse::Report(report)) => findings.extend(report),
+                Ok(_) => (),
+                Err(why) => findings.push(Finding::error(
+                    "db",
+                    describe_error(&why),
+                    "Run `rusteze init` to create the db, or point the db setting to an existing one".to_owned(),
+                )),
+            }
+            findings.extend(vault_findings(settings)?);
+        }
+        let failed = findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .count();
+        if matches.is_present("vacuum") {
+            if failed == 0 {
+                action_router(settings, Action::Vacuum)?;
+                findings.push(Finding::ok("vacuum", "Db compacted".to_owned()));
             } else {
-                //Server
+                findings.push(Finding::warning(
+                    "vacuum",
+                    "Skipped while there are errors".to_owned(),
+                    "Repair the errors above first".to_owned(),
+                ));
+            }
+        }
+        for finding in &findings {
+            let severity = match finding.severity {
+                Severity::Ok => "ok",
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            println!("{:<8} {:<11} {}", severity, finding.check, finding.message);
+            if let Some(suggestion) = &finding.suggestion {
+                println!("{:<20} fix: {}", "", suggestion);
             }
         }
+        if failed > 0 {
+            return Err(ActionErr::build(ActionErrType::TestFailed)
+                .with_source(format!("{} check(s) failed", failed)));
+        }
     }
+    Ok(())
 }
 
-pub fn handle_remove(matches: &ArgMatches, settings: &Settings) {
-    if let Some(matches) = matches.subcommand_matches("remove") {
-        if let Some(id) = matches.value_of("input").map(|id| id.trim().parse::<i64>()) {
-            match id {
-                Ok(record_id) => {
-                    let message = format!("a record id : {}", record_id);
-                    if remove_confirmation(&message) {
-                        if let Ok(response) = action_router(settings, Action::DeleteById(record_id))
-                        {
-                            match response {
-                                ActionResponse::Done => {
-                                    println!("Successfuly removed a record id {}", record_id)
-                                }
-                                _ => println!("Record not found"),
-                            }
-                        } else {
-                            //Server
-                        }
+/// Whether the config and the files next to the db agree with each other
+fn config_findings(settings: &Settings) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if settings.is_config_available() {
+        findings.push(Finding::ok("config", format!("Using {}", settings.get_db())));
+    } else {
+        findings.push(Finding::error(
+            "config",
+            format!("No configuration file found at {}", paths::config_file().display()),
+            "Run `rusteze init`, or point --home or RUSTEZE_HOME at the directory holding app.conf"
+                .to_owned(),
+        ));
+    }
+    for warning in settings.get_warnings() {
+        findings.push(Finding::warning(
+            "config",
+            format!("{} in {}", warning, paths::config_file().display()),
+            "Fix the spelling of the key or remove it".to_owned(),
+        ));
+    }
+    for stray in settings.stray_stores() {
+        findings.push(Finding::warning(
+            "config",
+            format!("{} exists but the config uses {}", stray, settings.get_db()),
+            "Set backend in app.conf to the store you use, or move the other one away".to_owned(),
+        ));
+    }
+    findings
+}
+
+/// Secrets left behind by servers that were purged
+fn vault_findings(settings: &Settings) -> Result<Vec<Finding>, ActionErr> {
+    let ids = secret_ids(&settings.get_vault()).map_err(vault_failed)?;
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut known: Vec<ID> = Vec::new();
+    if let ActionResponse::All(trashed) = action_router(settings, Action::FetchTrash)? {
+        known.extend(trashed.iter().filter_map(|server| server.id.map(ID::from)));
+    }
+    let mut findings = Vec::new();
+    for id in ids {
+        let live = matches!(action_router(settings, Action::FetchById(id))?, ActionResponse::One(Some(_)));
+        if !live && !known.contains(&id) {
+            findings.push(Finding::warning(
+                "vault",
+                format!("Secret stored for the missing server id {}", id),
+                format!("Remove it with `rusteze secret rm -i {}`", id),
+            ));
+        }
+    }
+    if findings.is_empty() {
+        findings.push(Finding::ok("vault", "Every secret belongs to a server".to_owned()));
+    }
+    Ok(findings)
+}
+
+/// An error with its causes on one line
+fn describe_error(why: &ActionErr) -> String {
+    let mut description = why.to_string();
+    let mut source = std::error::Error::source(why);
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    description
+}
+
+pub fn handle_restore(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("restore") {
+        if let Some(input) = matches.value_of("input") {
+            let resolved = action_router(settings, Action::ResolveTrashed(input.to_owned()));
+            let record_id = resolved_id(input.trim(), resolved)?;
+            action_router(settings, Action::Restore(record_id))?;
+            println!("Successfuly restored a record id {}", record_id);
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_trash(matches: &ArgMatches, settings: &Settings) -> Result<(), ActionErr> {
+    if let Some(matches) = matches.subcommand_matches("trash") {
+        if matches.subcommand_matches("list").is_some() {
+            match action_router(settings, Action::FetchTrash)? {
+                ActionResponse::All(servers) => {
+                    for server in servers {
+                        let serialized_server = serde_json::to_string(&server).unwrap();
+                        println!("{}", serialized_server);
                     }
                 }
-                Err(_) => println!("Not a valid integer"),
+                _ => eprintln!("Trash is empty"),
             }
-        } else {
-            if remove_confirmation("all records") {
-                if let Ok(response) = action_router(settings, Action::Delete) {
-                    match response {
-                        ActionResponse::Done => println!("Remove all successful "),
-                        _ => println!("Record not found"),
-                    }
+        } else if let Some(matches) = matches.subcommand_matches("purge") {
+            let older_than = match matches.value_of("older-than") {
+                Some(age) => Some(parse_age(age).ok_or_else(|| {
+                    ActionErr::build(ActionErrType::InvalidInput(
+                        "Not a valid age, use a number followed by s, m, h, d or w".to_owned(),
+                    ))
+                })?),
+                None => None,
+            };
+            if remove_confirmation("the trashed records permanently")? {
+                if let ActionResponse::Purged(count) =
+                    action_router(settings, Action::PurgeTrash(older_than))?
+                {
+                    println!("Purged {} record(s)", count);
                 }
-            } else {
-                //Server : ignore
             }
         }
     }
+    Ok(())
+}
+
+/// Parses an age such as `90s`, `15m`, `12h`, `30d` or `2w` into seconds,
+/// a bare number being read as days
+fn parse_age(age: &str) -> Option<i64> {
+    let age = age.trim();
+    let (number, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => age.split_at(index),
+        None => (age, "d"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<i64>().ok()?.checked_mul(multiplier)
+}
+
+fn fetch_server(settings: &Settings, record_id: ID) -> Result<Server, ActionErr> {
+    match action_router(settings, Action::FetchById(record_id))? {
+        ActionResponse::One(Some(server)) => Ok(server),
+        _ => Err(ActionErr::build(ActionErrType::RecordNotFound)),
+    }
+}
+
+/// Turns an id, alias or title given on the command line into a record id,
+/// asking the user to choose when several servers match
+fn select_server(settings: &Settings, input: &str) -> Result<ID, ActionErr> {
+    let input = input.trim();
+    // Ids are looked up too, so that another owner's or a purged server is not found
+    let action = match input.parse::<ID>() {
+        Ok(record_id) => Action::FetchById(record_id),
+        Err(_) => Action::Resolve(input.to_owned()),
+    };
+    resolved_id(input, action_router(settings, action))
+}
+
+/// The id of the server a lookup found, letting the user pick one when the
+/// input matched several
+fn resolved_id(input: &str, resolved: Result<ActionResponse, ActionErr>) -> Result<ID, ActionErr> {
+    match resolved {
+        Ok(ActionResponse::One(Some(server))) => server
+            .id
+            .map(ID::from)
+            .ok_or_else(|| ActionErr::build(ActionErrType::RecordNotFound)),
+        Ok(_) => Err(ActionErr::build(ActionErrType::RecordNotFound)),
+        Err(why) => match why.error_type() {
+            ActionErrType::AmbiguousRecord(candidates) => pick_candidate(input, candidates),
+            _ => Err(why),
+        },
+    }
+}
+
+/// Lets the user pick a server with the fuzzy finder, most recently
+/// connected first. `None` when the picker was cancelled.
+fn pick_server(settings: &Settings) -> Result<Option<ID>, ActionErr> {
+    if !stdin().is_terminal() || !stdout().is_terminal() {
+        return Err(ActionErr::build(ActionErrType::InvalidInput(
+            "Give the server with -i, the picker needs a terminal".to_owned(),
+        )));
+    }
+    let query = ServerQuery {
+        sort: SortField::LastConnected,
+        descending: true,
+        ..ServerQuery::default()
+    };
+    let servers = match action_router(settings, Action::Fetch(query))? {
+        ActionResponse::All(servers) => servers,
+        _ => return Err(ActionErr::build(ActionErrType::RecordNotFound)),
+    };
+    let picked = picker::pick(servers, describe_server, describe_details).map_err(|why| {
+        ActionErr::build(ActionErrType::ActionFailed("Unable to show the server picker".to_owned())).with_source(why)
+    })?;
+    Ok(picked.and_then(|server| server.id).map(ID::from))
+}
+
+fn pick_candidate(input: &str, candidates: &[Server]) -> Result<ID, ActionErr> {
+    eprintln!("\"{}\" matches more than one server", input);
+    for (index, server) in candidates.iter().enumerate() {
+        eprintln!("  [{}] {}", index + 1, describe_server(server));
+    }
+    let ambiguous = || {
+        ActionErr::build(ActionErrType::InvalidInput(
+            "Use an id or a unique alias to select one server".to_owned(),
+        ))
+    };
+    if !stdin().is_terminal() {
+        return Err(ambiguous());
+    }
+    let mut choice = String::new();
+    print!("Select a server (1-{}) {} ", candidates.len(), DELIMETER);
+    let _ = stdout().flush();
+    stdin()
+        .read_line(&mut choice)
+        .map_err(|why| invalid_input("Unable to read the choice", why))?;
+    clean_input(&mut choice);
+    match choice.trim().parse::<usize>() {
+        Ok(index) if index >= 1 && index <= candidates.len() => candidates[index - 1]
+            .id
+            .map(ID::from)
+            .ok_or_else(ambiguous),
+        _ => Err(ambiguous()),
+    }
+}
+
+fn describe_server(server: &Server) -> String {
+    let host = server
+        .domain
+        .as_ref()
+        .filter(|domain| !domain.is_empty())
+        .or(server.ip.as_ref())
+        .map(String::as_str)
+        .unwrap_or("");
+    let alias = match &server.alias {
+        Some(alias) => format!(" ({})", alias),
+        None => String::new(),
+    };
+    format!(
+        "id {} - {}{} {}@{}",
+        server.id.unwrap_or_default(),
+        server.title,
+        alias,
+        server.user_name,
+        host
+    )
+}
+
+/// Multi-line view of one server used by `show`
+fn describe_details(server: &Server) -> String {
+    let mut details = format!("{}\n", describe_server(server));
+    let field = |name: &str, value: &str| format!("  {:<16}{}\n", name, value);
+    if let Some(domain) = server.domain.as_ref().filter(|domain| !domain.is_empty()) {
+        details.push_str(&field("domain", domain));
+    }
+    if let Some(ip) = server.ip.as_ref().filter(|ip| !ip.is_empty()) {
+        details.push_str(&field("ip", ip));
+    }
+    if let Some(port) = server.port {
+        details.push_str(&field("port", &port.to_string()));
+    }
+    details.push_str(&field("owner", server.owner.as_deref().unwrap_or_default()));
+    let last_connected = match server.last_connected {
+        Some(timestamp) => describe_age(timestamp),
+        None => "never".to_owned(),
+    };
+    details.push_str(&field("last connected", &last_connected));
+    if !server.attributes.is_empty() {
+        details.push_str("\nAttributes\n");
+        for (name, value) in &server.attributes {
+            details.push_str(&field(name, value));
+        }
+    }
+    if let Some(notes) = &server.notes {
+        details.push_str("\nNotes\n");
+        for line in notes.lines() {
+            if !line.is_empty() {
+                details.push_str("  ");
+            }
+            details.push_str(line);
+            details.push('\n');
+        }
+    }
+    details
 }
+
+/// Flags of `add` that skip the prompts
+const ADD_FLAGS: [&str; 6] = ["title", "alias", "domain", "ip", "user", "port"];
+
+fn server_from_flags(matches: &ArgMatches) -> Result<Server, ActionErr> {
+    let value = |flag| matches.value_of(flag).map(str::to_owned);
+    let port = match matches.value_of("port") {
+        Some(port) => Some(port.parse::<u16>().map_err(|why| {
+            ActionErr::build(ActionErrType::InvalidInput(format!("Invalid port {}", port))).with_source(why)
+        })?),
+        None => None,
+    };
+    let server = Server {
+        alias: value("alias"),
+        port,
+        ..Server::new(
+            value("title").unwrap_or_default(),
+            value("domain"),
+            value("ip"),
+            value("user").unwrap_or_default(),
+        )
+    };
+    validate_server(server)
+}
+
+/// Reads one JSON server per line, skipping blank lines. Every invalid line
+/// is reported and none of the servers is returned when there is one.
+fn read_server_lines(input: impl BufRead) -> Result<Vec<Server>, ActionErr> {
+    let mut servers = Vec::new();
+    let mut invalid = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line.map_err(|why| invalid_input("Unable to read the servers", why))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = serde_json::from_str::<Server>(&line)
+            .map_err(|why| ActionErr::build(ActionErrType::InvalidInput(why.to_string())))
+            .and_then(validate_server);
+        match parsed {
+            Ok(server) => servers.push(Server {
+                id: None,
+                owner: None,
+                last_connected: None,
+                deleted_at: None,
+                ..server
+            }),
+            Err(why) => {
+                eprintln!("Line {}: {}", index + 1, why);
+                invalid += 1;
+            }
+        }
+    }
+    if invalid > 0 {
+        return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+            "{} invalid line(s), no server was saved",
+            invalid
+        ))));
+    }
+    Ok(servers)
+}
+
 fn retry_prompt(message: String){
-    println!("{} ! Please retry",message);
+    eprintln!("{} ! Please retry",message);
 }
 fn read_add_input() -> Result<Server, std::io::Error> {
     let mut title = String::new();
+    let mut alias_str = String::new();
     let mut domain_str = String::new();
     let mut ip_str = String::new();
     let mut user_name = String::new();
 
-    fn get_input(input: &mut String, msg: &str, error: &str) {
+    fn get_input(input: &mut String, msg: &str) -> Result<(), std::io::Error> {
         print!("{} {}", msg, DELIMETER);
         let _ = stdout().flush();
-        stdin().read_line(input).expect(error);
+        if stdin().read_line(input)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Input ended before all fields were entered",
+            ));
+        }
         clean_input(input);
+        Ok(())
     }
-    let error_message = "Did not enter a correct string";
-    get_input(&mut title, "Title", error_message);
-    get_input(&mut domain_str, "Domain", error_message);
-    get_input(&mut ip_str, "Ip", error_message);
-    get_input(&mut user_name, "Username", error_message);
-    if domain_str.is_empty() && ip_str.is_empty() {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid Ip or Domain",
-        ))
-    } else {
-        let domain = if domain_str.is_empty() {
-            None
-        } else {
-            Some(domain_str)
-        };
-        let ip = if ip_str.is_empty() {
-            None
-        } else {
-            Some(ip_str)
-        };
-        Ok(Server {
-            id: Option::None,
-            title,
-            domain,
-            ip,
-            user_name,
-            owner: Option::None,
-        })
-    }
+    get_input(&mut title, "Title")?;
+    get_input(&mut alias_str, "Alias (optional)")?;
+    get_input(&mut domain_str, "Domain")?;
+    get_input(&mut ip_str, "Ip")?;
+    get_input(&mut user_name, "Username")?;
+    validate_server(Server {
+        alias: Some(alias_str),
+        ..Server::new(title, Some(domain_str), Some(ip_str), user_name)
+    })
+    .map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why.to_string()))
 }
 fn clean_input(s: &mut String) {
     if let Some('\n') = s.chars().next_back() {
@@ -220,7 +1303,7 @@ fn clean_input(s: &mut String) {
     }
 }
 
-fn remove_confirmation(message: &str) -> bool {
+fn remove_confirmation(message: &str) -> Result<bool, ActionErr> {
     let mut confirmation = String::new();
     print!(
         "Do you want to remove {} (press enter to continue or type (N/n)) {} ",
@@ -229,11 +1312,77 @@ fn remove_confirmation(message: &str) -> bool {
     let _ = stdout().flush();
     stdin()
         .read_line(&mut confirmation)
-        .expect("Did not enter a correct string");
+        .map_err(|why| invalid_input("Unable to read the confirmation", why))?;
     clean_input(&mut confirmation);
-    if confirmation.eq_ignore_ascii_case("N") || confirmation.eq_ignore_ascii_case("n") {
-        return false;
-    } else {
-        return true;
+    Ok(!confirmation.eq_ignore_ascii_case("n"))
+}
+
+/// Whether to reopen a configuration that does not load, no when stdin is closed
+fn edit_again_confirmation() -> bool {
+    let mut confirmation = String::new();
+    print!("Edit the configuration again (press enter to continue or type (N/n)) {} ", DELIMETER);
+    let _ = stdout().flush();
+    match stdin().read_line(&mut confirmation) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => {
+            clean_input(&mut confirmation);
+            !confirmation.eq_ignore_ascii_case("n")
+        }
+    }
+}
+
+/// Whether to open that many panes, no when stdin is closed
+fn open_confirmation(count: usize) -> bool {
+    let mut confirmation = String::new();
+    print!("Do you want to open {} panes (press enter to continue or type (N/n)) {} ", count, DELIMETER);
+    let _ = stdout().flush();
+    match stdin().read_line(&mut confirmation) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => {
+            clean_input(&mut confirmation);
+            !confirmation.eq_ignore_ascii_case("n")
+        }
     }
 }
+
+#[test]
+fn parse_attribute_test() {
+    assert_eq!(parse_attribute("dc = ams").unwrap(), ("dc".to_owned(), "ams".to_owned()));
+    assert_eq!(parse_attribute("url=a=b").unwrap(), ("url".to_owned(), "a=b".to_owned()));
+    assert!(parse_attribute("dc").is_err());
+    assert!(parse_attribute("=ams").is_err());
+    assert_eq!(parse_attribute_filter("os"), ("os".to_owned(), None));
+    assert_eq!(parse_attribute_filter("os=linux"), ("os".to_owned(), Some("linux".to_owned())));
+}
+
+#[test]
+fn parse_age_test() {
+    assert_eq!(parse_age("90s"), Some(90));
+    assert_eq!(parse_age("12h"), Some(12 * 60 * 60));
+    assert_eq!(parse_age("30"), Some(30 * 24 * 60 * 60));
+    assert_eq!(parse_age("2w"), Some(14 * 24 * 60 * 60));
+    assert_eq!(parse_age("d"), None);
+    assert_eq!(parse_age("3y"), None);
+}
+
+#[test]
+fn read_server_lines_test() {
+    let input = concat!(
+        r#"{"title":"web","domain":"web.example.com","user_name":"admin","port":2222}"#,
+        "\n\n",
+        r#"{"id":7,"title":"db","ip":"10.0.0.9","user_name":"root","alias":"db1"}"#,
+        "\n",
+    );
+    let servers = read_server_lines(input.as_bytes()).unwrap();
+    assert_eq!(servers.len(), 2);
+    assert_eq!(servers[0].port, Some(2222));
+    assert_eq!(servers[1].id, None);
+
+    let invalid = concat!(
+        r#"{"title":"web","user_name":"admin"}"#,
+        "\n",
+        r#"{"title":"db","ip":"10.0.0.9","user_name":"root","alias":"two words"}"#,
+        "\nnot json\n",
+    );
+    assert!(read_server_lines(invalid.as_bytes()).is_err());
+}
@@ -0,0 +1,141 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+
+use log::debug;
+
+use crate::memory::Inventory;
+use crate::persistence::{CrudAction, DbError, Repository, Response};
+
+/// Keeps the inventory in a pretty printed JSON file that can be edited by
+/// hand. Every action reads the file and writes actions save it back, holding
+/// `<path>.lock` meanwhile so concurrent invocations take turns.
+pub struct JsonRepository {
+    path: String,
+}
+
+impl JsonRepository {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+
+    /// Locks the store until the returned file is dropped, exclusively when
+    /// the inventory is saved back
+    fn lock(&self, exclusive: bool) -> Result<File, DbError> {
+        if !Path::new(&self.path).exists() {
+            return Err(DbError::NotInitialized(self.path.to_owned()));
+        }
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(format!("{}.lock", self.path))?;
+        if exclusive {
+            lock.lock()?;
+        } else {
+            lock.lock_shared()?;
+        }
+        Ok(lock)
+    }
+
+    fn load(&self) -> Result<Inventory, DbError> {
+        if !Path::new(&self.path).exists() {
+            return Err(DbError::NotInitialized(self.path.to_owned()));
+        }
+        debug!("Reading the json store {}", self.path);
+        let content = fs::read_to_string(&self.path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Writes through a temporary file so an interrupted save keeps the old content
+    fn save(&self, inventory: &Inventory) -> Result<(), DbError> {
+        let temporary = format!("{}.tmp", self.path);
+        fs::write(&temporary, serde_json::to_string_pretty(inventory)?)?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+fn read_backup(source: &Path) -> Result<Inventory, DbError> {
+    let content = fs::read_to_string(source)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+impl Repository for JsonRepository {
+    fn initialize(&self) -> Result<Response, DbError> {
+        if !Path::new(&self.path).exists() {
+            self.save(&Inventory::initial())?;
+        }
+        Ok(Response::Success)
+    }
+
+    fn execute(&self, action: CrudAction, owner: &str) -> Result<Response, DbError> {
+        let is_write = action.is_write();
+        let _lock = self.lock(is_write)?;
+        let mut inventory = self.load()?;
+        let response = inventory.execute(action, owner)?;
+        if is_write {
+            self.save(&inventory)?;
+        }
+        Ok(response)
+    }
+
+    fn execute_all(&self, actions: Vec<CrudAction>, owner: &str) -> Result<Vec<Response>, DbError> {
+        let is_write = actions.iter().any(CrudAction::is_write);
+        let _lock = self.lock(is_write)?;
+        let mut inventory = self.load()?;
+        // Nothing is saved unless every action succeeded
+        let responses = inventory.execute_all(actions, owner)?;
+        if is_write {
+            self.save(&inventory)?;
+        }
+        Ok(responses)
+    }
+
+    fn backup(&self, target: &Path) -> Result<(), DbError> {
+        if target.exists() {
+            return Err(DbError::Rejected(format!("{} already exists", target.display())));
+        }
+        let _lock = self.lock(false)?;
+        self.load()?;
+        fs::copy(&self.path, target)?;
+        Ok(())
+    }
+
+    fn verify_backup(&self, source: &Path) -> Result<(), DbError> {
+        read_backup(source).map(|_| ())
+    }
+
+    fn restore(&self, source: &Path) -> Result<(), DbError> {
+        let inventory = read_backup(source)?;
+        let _lock = self.lock(true)?;
+        self.save(&inventory)
+    }
+}
+
+#[test]
+fn concurrent_save_test() {
+    use crate::domain::{Server, ServerQuery, DEFAULT_USER};
+
+    let path = std::env::temp_dir().join(format!("rusteze-json-test-{}.json", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    JsonRepository::new(path.clone()).initialize().unwrap();
+    let writers: Vec<_> = (0..8)
+        .map(|index| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let server = Server::new(format!("web-{}", index), None, Some("10.0.0.1".to_owned()), "admin".to_owned());
+                JsonRepository::new(path).execute(CrudAction::Save(server), DEFAULT_USER).unwrap();
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    match JsonRepository::new(path.clone()).execute(CrudAction::FindAll(ServerQuery::default()), DEFAULT_USER) {
+        Ok(Response::List(servers)) => assert_eq!(servers.len(), 8),
+        _ => panic!("expected a list"),
+    }
+    for file in [path.clone(), format!("{}.lock", path)] {
+        fs::remove_file(file).unwrap();
+    }
+}
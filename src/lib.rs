@@ -0,0 +1,36 @@
+//! The server inventory of rusteze, shared by the command line and other
+//! tools. Nothing here prints to stdout: every action returns its result or
+//! an `ActionErr`, and progress goes through the `log` crate.
+//!
+//! ```no_run
+//! use rusteze::{action_router, Action, ActionResponse, ServerQuery, Settings};
+//!
+//! let settings = Settings::load()?;
+//! if let ActionResponse::All(servers) = action_router(&settings, Action::Fetch(ServerQuery::default()))? {
+//!     for server in servers {
+//!         println!("{}", server.title);
+//!     }
+//! }
+//! # Ok::<(), rusteze::ActionErr>(())
+//! ```
+
+pub mod backup;
+pub mod config;
+pub mod connector;
+pub mod domain;
+mod json_store;
+mod memory;
+pub mod paths;
+pub mod persistence;
+pub mod service;
+pub mod transfer;
+pub mod vault;
+
+pub use connector::{ssh_arguments, Launch};
+pub use domain::{
+    Action, ActionErr, ActionErrType, ActionResponse, ConflictPolicy, Hooks, ImportSummary, Launcher, Server,
+    ServerQuery, Settings, SortField, DEFAULT_USER, ID,
+};
+pub use memory::MemoryRepository;
+pub use persistence::{open_repository, Repository};
+pub use service::{action_router, route};
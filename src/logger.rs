@@ -0,0 +1,169 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use rusteze::backup::timestamp;
+use rusteze::domain::now;
+
+/// Name of the log file in the data directory
+pub const LOG_FILE_NAME: &str = "rusteze.log";
+/// Size past which the log file is rotated when opened
+pub const MAX_LOG_SIZE: u64 = 1024 * 1024;
+/// Rotated log files kept next to the current one
+pub const KEEP_LOGS: usize = 3;
+
+/// Where records go besides stderr
+enum FileSink {
+    /// Records kept until the configuration tells whether there is a log file
+    Pending(Vec<(Level, String)>),
+    Open(File, LevelFilter),
+    Off,
+}
+
+/// Writes records up to the verbosity to stderr, and up to the configured
+/// level to the log file
+struct Logger {
+    console: LevelFilter,
+    file: Mutex<FileSink>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.console {
+            eprintln!("{}: {}", record.level().as_str().to_lowercase(), record.args());
+        }
+        let line = format!(
+            "{} {:<5} {} {}\n",
+            timestamp(now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Ok(mut file) = self.file.lock() {
+            match &mut *file {
+                FileSink::Pending(records) => records.push((record.level(), line)),
+                FileSink::Open(file, level) if record.level() <= *level => {
+                    let _ = file.write_all(line.as_bytes());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            if let FileSink::Open(file, _) = &mut *file {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// The stderr level for `-q` and the number of `-v`, warnings by default
+pub fn verbosity(quiet: bool, verbose: u64) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Installs the logger. Records are held back for the log file until
+/// `open_file` is called with the configured level.
+pub fn init(console: LevelFilter) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        console,
+        file: Mutex::new(FileSink::Pending(Vec::new())),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// Starts writing the records up to `level` to `path`, rotating it first
+/// when it grew too large. The records held back so far are written first.
+pub fn open_file(level: LevelFilter, path: &Path) -> io::Result<()> {
+    let logger = match LOGGER.get() {
+        Some(logger) => logger,
+        None => return Ok(()),
+    };
+    let pending = match logger.file.lock() {
+        Ok(mut file) => std::mem::replace(&mut *file, FileSink::Off),
+        Err(_) => return Ok(()),
+    };
+    log::set_max_level(logger.console.max(level));
+    if level == LevelFilter::Off {
+        return Ok(());
+    }
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    rotate(path, MAX_LOG_SIZE, KEEP_LOGS)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if let FileSink::Pending(records) = pending {
+        for (_, line) in records.iter().filter(|(record_level, _)| *record_level <= level) {
+            file.write_all(line.as_bytes())?;
+        }
+    }
+    if let Ok(mut sink) = logger.file.lock() {
+        *sink = FileSink::Open(file, level);
+    }
+    Ok(())
+}
+
+/// Moves `path` to `path.1`, `path.1` to `path.2` and so on once it reaches
+/// `max_size`, the oldest beyond `keep` being overwritten
+pub fn rotate(path: &Path, max_size: u64, keep: usize) -> io::Result<()> {
+    let size = fs::metadata(path).map(|metadata| metadata.len()).unwrap_or_default();
+    if size < max_size || keep == 0 {
+        return Ok(());
+    }
+    let numbered = |index: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    };
+    for index in (1..keep).rev() {
+        if numbered(index).exists() {
+            fs::rename(numbered(index), numbered(index + 1))?;
+        }
+    }
+    fs::rename(path, numbered(1))
+}
+
+#[test]
+fn rotate_log_test() {
+    let directory = std::env::temp_dir().join(format!("rusteze-log-test-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join(LOG_FILE_NAME);
+    let read = |name: &str| fs::read_to_string(directory.join(name)).unwrap_or_default();
+
+    fs::write(&path, "small\n").unwrap();
+    rotate(&path, 10, 2).unwrap();
+    assert_eq!(read("rusteze.log"), "small\n");
+
+    for content in ["first run\n", "second run\n", "third run\n"] {
+        fs::write(&path, content).unwrap();
+        rotate(&path, 5, 2).unwrap();
+    }
+    assert!(!path.exists());
+    assert_eq!(read("rusteze.log.1"), "third run\n");
+    assert_eq!(read("rusteze.log.2"), "second run\n");
+    assert!(!directory.join("rusteze.log.3").exists());
+    fs::remove_dir_all(&directory).unwrap();
+
+    assert_eq!(verbosity(false, 0), LevelFilter::Warn);
+    assert_eq!(verbosity(false, 2), LevelFilter::Debug);
+    assert_eq!(verbosity(true, 2), LevelFilter::Error);
+}
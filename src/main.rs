@@ -1,43 +1,115 @@
-mod config;
-mod connector;
-mod domain;
+
+mod browser;
+mod completion;
 mod handler;
-mod persistence;
-mod service;
+mod logger;
+mod output;
+mod picker;
 
 
-use clap::{load_yaml, App, ArgMatches};
+use clap::ArgMatches;
+use rusteze::vault::{self, Askpass};
+use rusteze::ActionErr;
+use std::error::Error;
+use std::process;
 use handler::{
-    handle_add, handle_config_argument, handle_connect, handle_init, handle_list, handle_remove,
-    handle_test,
+    command_line, handle_add, handle_attr, handle_complete, handle_completions, handle_config,
+    handle_config_argument, handle_connect, handle_db, handle_doctor, handle_export,
+    handle_group_connect, handle_import, handle_init, handle_list, handle_note, handle_remove,
+    handle_restore, handle_secret, handle_set, handle_show, handle_test, handle_trash, handle_tui,
+    handle_user,
 };
-/// Command line todo application
+/// Command line application managing ssh servers, on top of the rusteze library
 /// Below actions can be performed using this application
 /// - Init
 /// - Test
 /// - Add
 /// - List by id
 /// - List all
+/// - Show a server with its attributes and notes
 /// - Remove all
 /// - Remove by id
-/// #Example
+/// - Restore from and purge the trash
+/// - Manage users
+/// - Export and import the servers as json or csv
+/// - Print shell completion scripts
+/// - Browse and manage the servers in a terminal interface
+/// - Connect to a group of servers in tiled tmux panes
+/// - Back up and restore the db
+/// - Check the db and the configuration with doctor
+///
+/// # Example
 /// ```
 /// xcon init
 /// ```
 /// The above command initialize the default database and application configuration
 ///
 fn main() {
-    let yaml = load_yaml!("cli.yml");
-    let matches = App::from(yaml).get_matches();
-    application(&matches);
+    if let Some(answer) = Askpass::requested() {
+        process::exit(askpass(answer));
+    }
+    let matches = command_line().get_matches();
+    logger::init(logger::verbosity(matches.is_present("quiet"), matches.occurrences_of("verbose")));
+    if let Err(why) = application(&matches) {
+        report(&why);
+        process::exit(why.exit_code());
+    }
+}
+
+fn application(matches: &ArgMatches) -> Result<(), ActionErr> {
+    let settings = handle_config_argument(matches)?;
+    handle_set(matches, &settings)?;
+    handle_config(matches, &settings)?;
+    handle_init(matches, &settings)?;
+    handle_test(matches, &settings)?;
+    handle_add(matches, &settings)?;
+    handle_list(matches, &settings)?;
+    handle_show(matches, &settings)?;
+    handle_attr(matches, &settings)?;
+    handle_note(matches, &settings)?;
+    handle_remove(matches, &settings)?;
+    handle_export(matches, &settings)?;
+    handle_import(matches, &settings)?;
+    handle_completions(matches, &settings)?;
+    handle_complete(matches, &settings)?;
+    handle_restore(matches, &settings)?;
+    handle_trash(matches, &settings)?;
+    handle_connect(matches,&settings)?;
+    handle_group_connect(matches, &settings)?;
+    handle_tui(matches, &settings)?;
+    handle_user(matches, &settings)?;
+    handle_secret(matches, &settings)?;
+    handle_db(matches, &settings)?;
+    handle_doctor(matches, &settings)?;
+    Ok(())
+}
+
+/// Answers ssh when it runs rusteze as its SSH_ASKPASS program. Only
+/// password and passphrase prompts are answered, anything else such as a host
+/// key confirmation is declined.
+fn askpass(answer: Result<String, vault::VaultError>) -> i32 {
+    let prompt = std::env::args().nth(1).unwrap_or_default().to_lowercase();
+    if !prompt.contains("password") && !prompt.contains("passphrase") {
+        return 1;
+    }
+    match answer {
+        Ok(secret) => {
+            println!("{}", secret);
+            0
+        }
+        Err(why) => {
+            eprintln!("Error: {}", why);
+            1
+        }
+    }
 }
 
-fn application(matches: &ArgMatches) {
-    let settings = handle_config_argument(matches);
-    handle_init(matches, &settings);
-    handle_test(matches, &settings);
-    handle_add(matches, &settings);
-    handle_list(matches, &settings);
-    handle_remove(matches, &settings);
-    handle_connect(matches,&settings);
+/// Prints an error and the chain of errors that caused it to stderr
+fn report(why: &ActionErr) {
+    eprintln!("Error: {}", why);
+    let mut source = why.source();
+    while let Some(cause) = source {
+        eprintln!("  Caused by: {}", cause);
+        source = cause.source();
+    }
 }
@@ -0,0 +1,495 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::domain::{now, Finding, Server, ServerQuery, SortField, User, DEFAULT_USER};
+use crate::persistence::{CrudAction, DbError, Repository, Response};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserRecord {
+    pub id: i32,
+    pub name: String,
+}
+
+/// Users and servers held outside of SQLite, applying the same rules as the
+/// SQLite backend. Servers keep their owner by name.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Inventory {
+    #[serde(default)]
+    pub users: Vec<UserRecord>,
+    #[serde(default)]
+    pub servers: Vec<Server>,
+    /// Id of the next server, kept so that ids of purged servers are not
+    /// handed out again
+    #[serde(default)]
+    pub next_id: i32,
+}
+
+fn is_owned_by(server: &Server, owner: &str) -> bool {
+    server.owner.as_deref() == Some(owner)
+}
+
+fn is_live(server: &Server) -> bool {
+    server.deleted_at.is_none()
+}
+
+impl Inventory {
+    /// An empty inventory holding only the default user
+    pub fn initial() -> Self {
+        Inventory {
+            users: vec![UserRecord {
+                id: 0,
+                name: DEFAULT_USER.to_owned(),
+            }],
+            servers: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn execute(&mut self, action: CrudAction, owner: &str) -> Result<Response, DbError> {
+        match action {
+            CrudAction::Save(server) => self.add_server(server, owner),
+            CrudAction::Update(id, server) => {
+                let alias_taken = server.alias.is_some()
+                    && self.live(owner).any(|s| s.alias == server.alias && !has_id(s, id));
+                if alias_taken {
+                    return Err(DbError::Rejected(format!(
+                        "Alias {} is already in use",
+                        server.alias.unwrap_or_default()
+                    )));
+                }
+                Ok(self.update(id, owner, |s| {
+                    s.title = server.title;
+                    s.alias = server.alias;
+                    s.domain = Some(server.domain.unwrap_or_default());
+                    s.ip = Some(server.ip.unwrap_or_default());
+                    s.port = server.port;
+                    s.user_name = server.user_name;
+                    s.notes = server.notes;
+                    s.attributes.extend(server.attributes);
+                }))
+            }
+            CrudAction::Find(id) => Ok(Response::One(self.live(owner).find(|s| has_id(s, id)).cloned())),
+            CrudAction::FindByAlias(alias) => Ok(Response::One(
+                self.live(owner)
+                    .find(|s| s.alias.as_deref() == Some(alias.as_str()))
+                    .cloned(),
+            )),
+            CrudAction::FindByName(name) => Ok(Response::List(self.search(&name, owner))),
+            CrudAction::FindAll(query) => Ok(Response::List(self.query(&query, owner))),
+            CrudAction::Touch(id) => {
+                let timestamp = now();
+                self.servers
+                    .iter_mut()
+                    .filter(|s| is_owned_by(s, owner) && has_id(s, id))
+                    .for_each(|s| s.last_connected = Some(timestamp));
+                Ok(Response::Success)
+            }
+            CrudAction::Remove(id) => Ok(match self.trash(owner, |s| has_id(s, id)) {
+                0 => Response::One(None),
+                _ => Response::Success,
+            }),
+            CrudAction::RemoveAll => {
+                self.trash(owner, |_| true);
+                Ok(Response::Success)
+            }
+            CrudAction::FindDeleted => {
+                let mut servers: Vec<Server> = self
+                    .servers
+                    .iter()
+                    .filter(|s| is_owned_by(s, owner) && !is_live(s))
+                    .cloned()
+                    .collect();
+                servers.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(a.id.cmp(&b.id)));
+                Ok(Response::List(servers))
+            }
+            CrudAction::Restore(id) => self.restore(id, owner),
+            CrudAction::Purge(older_than) => {
+                let cutoff = now() - older_than.unwrap_or(0);
+                let before = self.servers.len();
+                self.servers.retain(|s| {
+                    !(is_owned_by(s, owner) && s.deleted_at.is_some_and(|at| at <= cutoff))
+                });
+                Ok(Response::Purged(before - self.servers.len()))
+            }
+            CrudAction::SetAttributes(id, attributes) => Ok(self.update(id, owner, |s| {
+                s.attributes.extend(attributes);
+            })),
+            CrudAction::RemoveAttributes(id, names) => Ok(self.update(id, owner, |s| {
+                names.iter().for_each(|name| {
+                    s.attributes.remove(name);
+                });
+            })),
+            CrudAction::SetNotes(id, notes) => Ok(self.update(id, owner, |s| s.notes = notes)),
+            CrudAction::SaveUser(name) => self.add_user(&name),
+            CrudAction::FindUsers => {
+                let mut users: Vec<User> = self
+                    .users
+                    .iter()
+                    .map(|user| User {
+                        id: Some(user.id),
+                        name: user.name.to_owned(),
+                        servers: self.live(&user.name).count() as i64,
+                    })
+                    .collect();
+                users.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(Response::Users(users))
+            }
+            CrudAction::RemoveUser(name) => self.remove_user(&name),
+            CrudAction::Diagnose => Ok(Response::Report(self.diagnose())),
+            CrudAction::Vacuum => Ok(Response::Success),
+        }
+    }
+
+    /// Runs the actions one after the other, stopping at the first failure.
+    /// Callers keep the inventory only when every action succeeded.
+    pub fn execute_all(&mut self, actions: Vec<CrudAction>, owner: &str) -> Result<Vec<Response>, DbError> {
+        actions.into_iter().map(|action| self.execute(action, owner)).collect()
+    }
+
+    /// The checks of the SQLite doctor that apply to a plain inventory
+    fn diagnose(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut ids: Vec<i32> = self.servers.iter().filter_map(|s| s.id).collect();
+        ids.sort_unstable();
+        let duplicates = ids.windows(2).filter(|pair| pair[0] == pair[1]).count();
+        if duplicates > 0 {
+            findings.push(Finding::error(
+                "integrity",
+                format!("{} server id(s) are used more than once", duplicates),
+                "Give each server in the file a unique id".to_owned(),
+            ));
+        } else {
+            findings.push(Finding::ok("integrity", "Server ids are unique".to_owned()));
+        }
+
+        let orphans: Vec<&str> = self
+            .servers
+            .iter()
+            .filter_map(|s| s.owner.as_deref())
+            .filter(|owner| !self.users.iter().any(|user| user.name == *owner))
+            .collect();
+        match orphans.first() {
+            Some(owner) => findings.push(Finding::error(
+                "owners",
+                format!("{} server(s) belong to missing users such as {}", orphans.len(), owner),
+                format!("Add the user with `rusteze user add {}`", owner),
+            )),
+            None => findings.push(Finding::ok("owners", "Every server has an owner".to_owned())),
+        }
+
+        let mut aliases: Vec<(&str, &str)> = self
+            .servers
+            .iter()
+            .filter(|s| is_live(s))
+            .filter_map(|s| Some((s.owner.as_deref()?, s.alias.as_deref()?)))
+            .collect();
+        aliases.sort_unstable();
+        match aliases.windows(2).find(|pair| pair[0] == pair[1]) {
+            Some(pair) => findings.push(Finding::warning(
+                "aliases",
+                format!("Alias {} is used by several servers of {}", pair[0].1, pair[0].0),
+                "Rename or remove one of them".to_owned(),
+            )),
+            None => findings.push(Finding::ok("aliases", "Aliases are unique".to_owned())),
+        }
+        findings
+    }
+
+    fn live<'a>(&'a self, owner: &'a str) -> impl Iterator<Item = &'a Server> + 'a {
+        self.servers
+            .iter()
+            .filter(move |s| is_owned_by(s, owner) && is_live(s))
+    }
+
+    fn alias_taken(&self, alias: &Option<String>, owner: &str) -> bool {
+        alias.is_some() && self.live(owner).any(|s| &s.alias == alias)
+    }
+
+    fn add_user(&mut self, name: &str) -> Result<Response, DbError> {
+        if self.users.iter().any(|user| user.name == name) {
+            return Err(DbError::Rejected(format!("User {} already exists", name)));
+        }
+        let id = self.users.iter().map(|user| user.id + 1).max().unwrap_or(0);
+        self.users.push(UserRecord {
+            id,
+            name: name.to_owned(),
+        });
+        Ok(Response::Success)
+    }
+
+    fn remove_user(&mut self, name: &str) -> Result<Response, DbError> {
+        let owned = self.servers.iter().filter(|s| is_owned_by(s, name)).count();
+        if owned > 0 {
+            return Err(DbError::Rejected(format!(
+                "User {} still owns {} server(s) including the trash, remove and purge them first",
+                name, owned
+            )));
+        }
+        match self.users.iter().position(|user| user.name == name) {
+            Some(index) => {
+                self.users.remove(index);
+                Ok(Response::Success)
+            }
+            None => Err(DbError::Rejected(format!("User {} does not exist", name))),
+        }
+    }
+
+    fn add_server(&mut self, server: Server, owner: &str) -> Result<Response, DbError> {
+        if !self.users.iter().any(|user| user.name == owner) {
+            return Err(DbError::Rejected(format!(
+                "User {} does not exist, add it with `rusteze user add {}`",
+                owner, owner
+            )));
+        }
+        if self.alias_taken(&server.alias, owner) {
+            return Err(DbError::Rejected(format!(
+                "Alias {} is already in use",
+                server.alias.unwrap_or_default()
+            )));
+        }
+        // Files written before the counter only have the ids to go by
+        let id = self.servers.iter().filter_map(|s| s.id).map(|id| id + 1).fold(self.next_id.max(1), i32::max);
+        self.next_id = id + 1;
+        self.servers.push(Server {
+            id: Some(id),
+            owner: Some(owner.to_owned()),
+            domain: Some(server.domain.unwrap_or_default()),
+            ip: Some(server.ip.unwrap_or_default()),
+            last_connected: None,
+            deleted_at: None,
+            ..server
+        });
+        Ok(Response::Success)
+    }
+
+    /// Moves the selected live servers to the trash, returning how many
+    fn trash<F: Fn(&Server) -> bool>(&mut self, owner: &str, selected: F) -> usize {
+        let timestamp = now();
+        let mut trashed = 0;
+        self.servers
+            .iter_mut()
+            .filter(|s| is_owned_by(s, owner) && is_live(s) && selected(s))
+            .for_each(|s| {
+                s.deleted_at = Some(timestamp);
+                trashed += 1;
+            });
+        trashed
+    }
+
+    /// Changes a live server, answering `One(None)` when there is none
+    fn update<F: FnOnce(&mut Server)>(&mut self, id: i64, owner: &str, change: F) -> Response {
+        match self
+            .servers
+            .iter_mut()
+            .find(|s| is_owned_by(s, owner) && is_live(s) && has_id(s, id))
+        {
+            Some(server) => {
+                change(server);
+                Response::Success
+            }
+            None => Response::One(None),
+        }
+    }
+
+    fn restore(&mut self, id: i64, owner: &str) -> Result<Response, DbError> {
+        let alias = match self
+            .servers
+            .iter()
+            .find(|s| is_owned_by(s, owner) && !is_live(s) && has_id(s, id))
+        {
+            Some(server) => server.alias.clone(),
+            None => return Ok(Response::One(None)),
+        };
+        if self.alias_taken(&alias, owner) {
+            return Err(DbError::Rejected(format!(
+                "Alias {} is already in use",
+                alias.unwrap_or_default()
+            )));
+        }
+        self.servers
+            .iter_mut()
+            .filter(|s| is_owned_by(s, owner) && has_id(s, id))
+            .for_each(|s| s.deleted_at = None);
+        Ok(Response::Success)
+    }
+
+    fn search(&self, name: &str, owner: &str) -> Vec<Server> {
+        let exact: Vec<Server> = self.live(owner).filter(|s| s.title == name).cloned().collect();
+        if !exact.is_empty() {
+            return exact;
+        }
+        let prefix = name.to_lowercase();
+        self.live(owner)
+            .filter(|s| {
+                s.title.to_lowercase().starts_with(&prefix)
+                    || s.alias
+                        .as_ref()
+                        .is_some_and(|alias| alias.to_lowercase().starts_with(&prefix))
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn query(&self, query: &ServerQuery, owner: &str) -> Vec<Server> {
+        let mut servers: Vec<Server> = self
+            .live(owner)
+            .filter(|s| query.user_name.as_ref().is_none_or(|user| &s.user_name == user))
+            .filter(|s| {
+                query.domain_suffix.as_ref().is_none_or(|suffix| {
+                    s.domain.as_ref().is_some_and(|domain| {
+                        domain.to_lowercase().ends_with(&suffix.to_lowercase())
+                    })
+                })
+            })
+            .filter(|s| {
+                query.attributes.iter().all(|(name, value)| match (s.attributes.get(name), value) {
+                    (Some(actual), Some(expected)) => actual == expected,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                })
+            })
+            .filter(|s| {
+                query
+                    .has_ip
+                    .is_none_or(|has_ip| s.ip.as_ref().is_some_and(|ip| !ip.is_empty()) == has_ip)
+            })
+            .cloned()
+            .collect();
+        servers.sort_by(|a, b| {
+            let order = match query.sort {
+                SortField::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+                SortField::Ip => a.ip.cmp(&b.ip),
+                SortField::Created => Ordering::Equal,
+                SortField::LastConnected => a.last_connected.cmp(&b.last_connected),
+            }
+            .then(a.id.cmp(&b.id));
+            if query.descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+        servers
+            .into_iter()
+            .skip(query.offset.unwrap_or(0) as usize)
+            .take(query.limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect()
+    }
+}
+
+fn has_id(server: &Server, id: i64) -> bool {
+    server.id.map(i64::from) == Some(id)
+}
+
+/// Keeps the inventory for the lifetime of the value only, for tests
+pub struct MemoryRepository {
+    inventory: std::cell::RefCell<Inventory>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self {
+            inventory: std::cell::RefCell::new(Inventory::initial()),
+        }
+    }
+}
+
+impl Default for MemoryRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repository for MemoryRepository {
+    fn initialize(&self) -> Result<Response, DbError> {
+        Ok(Response::Success)
+    }
+
+    fn execute(&self, action: CrudAction, owner: &str) -> Result<Response, DbError> {
+        self.inventory.borrow_mut().execute(action, owner)
+    }
+
+    fn execute_all(&self, actions: Vec<CrudAction>, owner: &str) -> Result<Vec<Response>, DbError> {
+        let mut inventory = self.inventory.borrow().clone();
+        let responses = inventory.execute_all(actions, owner)?;
+        *self.inventory.borrow_mut() = inventory;
+        Ok(responses)
+    }
+
+    fn backup(&self, target: &std::path::Path) -> Result<(), DbError> {
+        if target.exists() {
+            return Err(DbError::Rejected(format!("{} already exists", target.display())));
+        }
+        std::fs::write(target, serde_json::to_string(&*self.inventory.borrow())?)?;
+        Ok(())
+    }
+
+    fn verify_backup(&self, source: &std::path::Path) -> Result<(), DbError> {
+        let content = std::fs::read_to_string(source)?;
+        serde_json::from_str::<Inventory>(&content)?;
+        Ok(())
+    }
+
+    fn restore(&self, source: &std::path::Path) -> Result<(), DbError> {
+        let content = std::fs::read_to_string(source)?;
+        *self.inventory.borrow_mut() = serde_json::from_str(&content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_server(title: &str, alias: Option<&str>) -> Server {
+    Server {
+        alias: alias.map(str::to_owned),
+        ..Server::new(title.to_owned(), Some(format!("{}.example.com", title)), None, "admin".to_owned())
+    }
+}
+
+#[test]
+fn inventory_rules_test() {
+    let repository = MemoryRepository::new();
+    let run = |action| repository.execute(action, DEFAULT_USER);
+    run(CrudAction::Save(test_server("web-1", Some("web")))).unwrap();
+    run(CrudAction::Save(test_server("web-2", None))).unwrap();
+    assert!(matches!(
+        run(CrudAction::Save(test_server("web-3", Some("web")))),
+        Err(DbError::Rejected(_))
+    ));
+    assert!(matches!(
+        repository.execute(CrudAction::Save(test_server("db", None)), "bob"),
+        Err(DbError::Rejected(_))
+    ));
+    assert!(matches!(run(CrudAction::FindByName("WEB".to_owned())), Ok(Response::List(ref s)) if s.len() == 2));
+
+    run(CrudAction::Remove(1)).unwrap();
+    assert!(matches!(run(CrudAction::Find(1)), Ok(Response::One(None))));
+    assert!(matches!(run(CrudAction::Remove(1)), Ok(Response::One(None))));
+    run(CrudAction::Save(test_server("web-3", Some("web")))).unwrap();
+    assert!(run(CrudAction::Restore(1)).is_err());
+    assert!(matches!(run(CrudAction::Purge(None)), Ok(Response::Purged(1))));
+
+    let query = ServerQuery {
+        sort: SortField::Title,
+        descending: true,
+        ..ServerQuery::default()
+    };
+    match run(CrudAction::FindAll(query)).unwrap() {
+        Response::List(servers) => assert_eq!(
+            servers.into_iter().map(|s| s.title).collect::<Vec<_>>(),
+            vec!["web-3", "web-2"]
+        ),
+        _ => panic!("expected a list"),
+    }
+
+    // The id of a purged server is not handed out again
+    run(CrudAction::Remove(3)).unwrap();
+    run(CrudAction::Purge(None)).unwrap();
+    run(CrudAction::Save(test_server("web-4", None))).unwrap();
+    assert!(matches!(run(CrudAction::Find(3)), Ok(Response::One(None))));
+    assert!(matches!(run(CrudAction::Find(4)), Ok(Response::One(Some(_)))));
+
+    let target = std::env::temp_dir().join(format!("rusteze-memory-backup-{}.json", std::process::id()));
+    repository.backup(&target).unwrap();
+    assert!(matches!(repository.backup(&target), Err(DbError::Rejected(_))));
+    std::fs::remove_file(&target).unwrap();
+}
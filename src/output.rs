@@ -0,0 +1,248 @@
+use serde_json::{Map, Value};
+
+use rusteze::domain::{now, ActionErr, ActionErrType, OutputFormat, Server};
+
+/// Columns of the table, csv and plain formats when none are selected
+pub const DEFAULT_COLUMNS: &str = "id,title,alias,user,host,port";
+
+/// A column of a server listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Id,
+    Title,
+    Alias,
+    Domain,
+    Ip,
+    /// The domain, or the ip without one
+    Host,
+    Port,
+    User,
+    Owner,
+    LastConnected,
+    /// `attr.<name>`, the value of an attribute
+    Attribute(String),
+}
+
+impl Column {
+    pub fn parse(name: &str) -> Option<Column> {
+        match name.trim() {
+            "id" => Some(Column::Id),
+            "title" => Some(Column::Title),
+            "alias" => Some(Column::Alias),
+            "domain" => Some(Column::Domain),
+            "ip" => Some(Column::Ip),
+            "host" => Some(Column::Host),
+            "port" => Some(Column::Port),
+            "user" => Some(Column::User),
+            "owner" => Some(Column::Owner),
+            "last-connected" => Some(Column::LastConnected),
+            name => name
+                .strip_prefix("attr.")
+                .filter(|attribute| !attribute.is_empty())
+                .map(|attribute| Column::Attribute(attribute.to_owned())),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Column::Id => "id".to_owned(),
+            Column::Title => "title".to_owned(),
+            Column::Alias => "alias".to_owned(),
+            Column::Domain => "domain".to_owned(),
+            Column::Ip => "ip".to_owned(),
+            Column::Host => "host".to_owned(),
+            Column::Port => "port".to_owned(),
+            Column::User => "user".to_owned(),
+            Column::Owner => "owner".to_owned(),
+            Column::LastConnected => "last-connected".to_owned(),
+            Column::Attribute(name) => format!("attr.{}", name),
+        }
+    }
+
+    fn value(&self, server: &Server) -> Value {
+        let text = |value: Option<&String>| match value.filter(|value| !value.is_empty()) {
+            Some(value) => Value::from(value.as_str()),
+            None => Value::Null,
+        };
+        match self {
+            Column::Id => server.id.map_or(Value::Null, Value::from),
+            Column::Title => Value::from(server.title.as_str()),
+            Column::Alias => text(server.alias.as_ref()),
+            Column::Domain => text(server.domain.as_ref()),
+            Column::Ip => text(server.ip.as_ref()),
+            Column::Host => match text(server.domain.as_ref()) {
+                Value::Null => text(server.ip.as_ref()),
+                domain => domain,
+            },
+            Column::Port => server.port.map_or(Value::Null, Value::from),
+            Column::User => Value::from(server.user_name.as_str()),
+            Column::Owner => text(server.owner.as_ref()),
+            Column::LastConnected => server.last_connected.map_or(Value::Null, Value::from),
+            Column::Attribute(name) => text(server.attributes.get(name)),
+        }
+    }
+
+    /// The value as read by a person, relative time included
+    fn text(&self, server: &Server) -> String {
+        match (self, self.value(server)) {
+            (Column::LastConnected, Value::Number(timestamp)) => {
+                describe_age(timestamp.as_i64().unwrap_or_default())
+            }
+            (_, Value::Null) => String::new(),
+            (_, Value::String(value)) => value,
+            (_, value) => value.to_string(),
+        }
+    }
+}
+
+/// Parses a comma separated list of column names
+pub fn parse_columns(list: &str) -> Result<Vec<Column>, ActionErr> {
+    list.split(',')
+        .map(|name| {
+            Column::parse(name).ok_or_else(|| {
+                ActionErr::build(ActionErrType::InvalidInput(format!(
+                    "Unknown column {}, expected id, title, alias, domain, ip, host, port, user, owner, last-connected or attr.<name>",
+                    name
+                )))
+            })
+        })
+        .collect()
+}
+
+/// Renders servers in `format`. The structured formats print whole servers
+/// unless columns are selected, the others the default columns.
+pub fn render(servers: &[Server], format: OutputFormat, columns: Option<&[Column]>) -> Result<String, ActionErr> {
+    let default_columns;
+    let selected = match columns {
+        Some(columns) => columns,
+        None => {
+            default_columns = parse_columns(DEFAULT_COLUMNS)?;
+            &default_columns
+        }
+    };
+    let rows = || -> Vec<Vec<String>> {
+        servers
+            .iter()
+            .map(|server| selected.iter().map(|column| column.text(server)).collect())
+            .collect()
+    };
+    let failed = |why: String| {
+        ActionErr::build(ActionErrType::ActionFailed("Unable to format the servers".to_owned())).with_source(why)
+    };
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(&records(servers, columns)).map(|json| json + "\n").map_err(|why| failed(why.to_string()))
+        }
+        OutputFormat::Jsonl => Ok(records(servers, columns).iter().map(|record| format!("{}\n", record)).collect()),
+        OutputFormat::Yaml => serde_yaml::to_string(&records(servers, columns)).map_err(|why| failed(why.to_string())),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer
+                .write_record(selected.iter().map(Column::name))
+                .map_err(|why| failed(why.to_string()))?;
+            for row in rows() {
+                writer.write_record(&row).map_err(|why| failed(why.to_string()))?;
+            }
+            let content = writer.into_inner().map_err(|why| failed(why.to_string()))?;
+            String::from_utf8(content).map_err(|why| failed(why.to_string()))
+        }
+        OutputFormat::Plain => Ok(rows().iter().map(|row| format!("{}\n", row.join("\t"))).collect()),
+        OutputFormat::Table => {
+            let header = selected.iter().map(|column| column.name().to_uppercase()).collect();
+            Ok(table(header, rows()))
+        }
+    }
+}
+
+/// Renders one server, as an object rather than a list in JSON
+pub fn render_one(server: Server, format: OutputFormat, columns: Option<&[Column]>) -> Result<String, ActionErr> {
+    let servers = [server];
+    match format {
+        OutputFormat::Json => Ok(format!("{:#}\n", records(&servers, columns).remove(0))),
+        _ => render(&servers, format, columns),
+    }
+}
+
+/// Servers as JSON values, only with the selected columns if any
+fn records(servers: &[Server], columns: Option<&[Column]>) -> Vec<Value> {
+    match columns {
+        Some(columns) => servers
+            .iter()
+            .map(|server| {
+                let record = columns
+                    .iter()
+                    .map(|column| (column.name(), column.value(server)))
+                    .collect::<Map<String, Value>>();
+                Value::Object(record)
+            })
+            .collect(),
+        None => servers
+            .iter()
+            .map(|server| serde_json::to_value(server).unwrap_or_default())
+            .collect(),
+    }
+}
+
+/// Aligns the columns of `rows` under `header`
+fn table(header: Vec<String>, rows: Vec<Vec<String>>) -> String {
+    let mut widths = header.iter().map(|name| name.chars().count()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    std::iter::once(header)
+        .chain(rows)
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            format!("{}\n", line.trim_end())
+        })
+        .collect()
+}
+
+/// How long ago a unix timestamp was, in the largest whole unit
+pub fn describe_age(timestamp: i64) -> String {
+    let elapsed = (now() - timestamp).max(0);
+    let (count, unit) = match elapsed {
+        0..=59 => (elapsed, "second"),
+        60..=3599 => (elapsed / 60, "minute"),
+        3600..=86399 => (elapsed / 3600, "hour"),
+        _ => (elapsed / 86400, "day"),
+    };
+    format!("{} {}(s) ago", count, unit)
+}
+
+#[test]
+fn render_test() {
+    let mut web = Server::new("web".to_owned(), Some("web.example.com".to_owned()), None, "admin".to_owned());
+    web.id = Some(1);
+    web.port = Some(2222);
+    web.attributes.insert("dc".to_owned(), "ams".to_owned());
+    let mut db = Server::new("database".to_owned(), Some(String::new()), Some("10.0.0.9".to_owned()), "root".to_owned());
+    db.id = Some(2);
+    db.alias = Some("db".to_owned());
+    let servers = [web, db];
+
+    assert_eq!(
+        render(&servers, OutputFormat::Table, None).unwrap(),
+        "ID  TITLE     ALIAS  USER   HOST             PORT\n\
+         1   web              admin  web.example.com  2222\n\
+         2   database  db     root   10.0.0.9\n"
+    );
+    let columns = parse_columns("title,attr.dc").unwrap();
+    assert_eq!(
+        render(&servers, OutputFormat::Csv, Some(&columns)).unwrap(),
+        "title,attr.dc\nweb,ams\ndatabase,\n"
+    );
+    assert_eq!(
+        render(&servers, OutputFormat::Jsonl, Some(&columns)).unwrap(),
+        "{\"title\":\"web\",\"attr.dc\":\"ams\"}\n{\"title\":\"database\",\"attr.dc\":null}\n"
+    );
+    assert!(render(&servers, OutputFormat::Yaml, None).unwrap().contains("title: database"));
+    assert!(parse_columns("id,colour").is_err());
+}
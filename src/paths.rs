@@ -0,0 +1,211 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::info;
+
+use crate::config::ConfigFile;
+use crate::domain::DEFAULT_DB_NAME;
+
+/// Name of the configuration file, also the name releases before the XDG
+/// layout looked for in the current directory
+pub const CONFIG_FILE_NAME: &str = "app.conf";
+const APPLICATION: &str = "rusteze";
+/// Left in the data directory once the files of an older release were moved
+const MIGRATED_MARKER: &str = ".migrated";
+/// The files an older release kept next to its configuration, after the db name
+const LEGACY_SUFFIXES: [&str; 6] = [".store", ".store-wal", ".store-shm", ".json", ".vault", ".backups"];
+
+/// Where rusteze keeps its configuration and its data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locations {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    /// Set with `RUSTEZE_CONFIG`, otherwise app.conf in the config directory
+    pub config_file: Option<PathBuf>,
+}
+
+static LOCATIONS: OnceLock<Locations> = OnceLock::new();
+
+impl Locations {
+    /// `home` (from `--home` or `RUSTEZE_HOME`) holds both the configuration
+    /// and the data, otherwise they follow the XDG base directories
+    pub fn resolve<F>(home: Option<PathBuf>, var: F) -> Locations
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        let config_file = var("RUSTEZE_CONFIG").map(PathBuf::from);
+        if let Some(home) = home.or_else(|| var("RUSTEZE_HOME").map(PathBuf::from)) {
+            return Locations {
+                config_dir: home.clone(),
+                data_dir: home,
+                config_file,
+            };
+        }
+        let user_home = var("HOME").map(PathBuf::from).unwrap_or_default();
+        let base = |variable: &str, fallback: &str| {
+            var(variable)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| user_home.join(fallback))
+                .join(APPLICATION)
+        };
+        Locations {
+            config_dir: base("XDG_CONFIG_HOME", ".config"),
+            data_dir: base("XDG_DATA_HOME", ".local/share"),
+            config_file,
+        }
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        match &self.config_file {
+            Some(config_file) => config_file.to_owned(),
+            None => self.config_dir.join(CONFIG_FILE_NAME),
+        }
+    }
+}
+
+/// Resolves the locations for this invocation, `home` being the `--home` flag
+pub fn init(home: Option<&str>) -> &'static Locations {
+    // Tests never touch the configuration of the user running them
+    let home = if cfg!(test) {
+        Some(env::temp_dir().join(format!("rusteze-test-{}", std::process::id())))
+    } else {
+        home.map(PathBuf::from)
+    };
+    LOCATIONS.get_or_init(|| Locations::resolve(home, |name| env::var(name).ok()))
+}
+
+/// The locations of this invocation, resolved from the environment when
+/// `init` was not called
+pub fn locations() -> &'static Locations {
+    init(None)
+}
+
+pub fn config_file() -> PathBuf {
+    locations().config_file()
+}
+
+/// Path of a data file, `name` being relative to the data directory
+pub fn data_file(name: &str) -> String {
+    locations().data_dir.join(name).to_string_lossy().into_owned()
+}
+
+/// Moves the configuration and the db files of releases that kept them in
+/// the current directory to their locations, when they are not there yet.
+/// Only done once, for the first app.conf found that loads as a rusteze
+/// configuration. Returns the moved files.
+pub fn migrate_legacy(from: &Path, locations: &Locations) -> io::Result<Vec<PathBuf>> {
+    let marker = locations.data_dir.join(MIGRATED_MARKER);
+    if marker.exists() {
+        return Ok(Vec::new());
+    }
+    let legacy_config = from.join(CONFIG_FILE_NAME);
+    let config_file = locations.config_file();
+    let mut moved = Vec::new();
+    if !config_file.exists() && !same_file(&legacy_config, &config_file) {
+        if let Some(db) = legacy_db(&legacy_config) {
+            fs::create_dir_all(config_file.parent().unwrap_or(&locations.config_dir))?;
+            fs::create_dir_all(&locations.data_dir)?;
+            moved.push(move_file(&legacy_config, &config_file)?);
+            for suffix in LEGACY_SUFFIXES {
+                let name = format!("{}{}", db, suffix);
+                let (path, target) = (from.join(&name), locations.data_dir.join(&name));
+                if path.exists() && !target.exists() {
+                    moved.push(move_file(&path, &target)?);
+                }
+            }
+            fs::write(&marker, "")?;
+        }
+    }
+    Ok(moved)
+}
+
+/// The db name of a configuration left by an older release, None when there
+/// is none or when it does not load as a rusteze configuration
+fn legacy_db(path: &Path) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    match ConfigFile::parse(&content) {
+        Ok((config, warnings))
+            if config != ConfigFile::default() && !warnings.iter().any(|warning| warning.starts_with("unknown key")) =>
+        {
+            Some(config.db.unwrap_or_else(|| DEFAULT_DB_NAME.to_owned()))
+        }
+        _ => None,
+    }
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<PathBuf> {
+    info!("Moving {} to {}", from.display(), to.display());
+    fs::rename(from, to)?;
+    Ok(to.to_path_buf())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[test]
+fn resolve_locations_test() {
+    let env = |name: &str| match name {
+        "HOME" => Some("/home/ada".to_owned()),
+        "XDG_DATA_HOME" => Some("/data".to_owned()),
+        _ => None,
+    };
+    let xdg = Locations::resolve(None, env);
+    assert_eq!(xdg.config_file(), PathBuf::from("/home/ada/.config/rusteze/app.conf"));
+    assert_eq!(xdg.data_dir, PathBuf::from("/data/rusteze"));
+
+    let home = Locations::resolve(Some(PathBuf::from("/srv/rz")), env);
+    assert_eq!(home.config_dir, PathBuf::from("/srv/rz"));
+    assert_eq!(home.data_dir, PathBuf::from("/srv/rz"));
+
+    let custom = Locations::resolve(None, |name| match name {
+        "RUSTEZE_CONFIG" => Some("/etc/rusteze.toml".to_owned()),
+        _ => env(name),
+    });
+    assert_eq!(custom.config_file(), PathBuf::from("/etc/rusteze.toml"));
+}
+
+#[test]
+fn migrate_legacy_test() {
+    let root = env::temp_dir().join(format!("rusteze-migrate-test-{}", std::process::id()));
+    let legacy = root.join("cwd");
+    fs::create_dir_all(legacy.join("prod.backups")).unwrap();
+    fs::write(legacy.join(CONFIG_FILE_NAME), "db=prod \nuser=root \n").unwrap();
+    fs::write(legacy.join("prod.store"), "").unwrap();
+    fs::write(legacy.join("other.store"), "").unwrap();
+    fs::write(legacy.join("prod.java"), "").unwrap();
+    let locations = Locations::resolve(Some(root.join("home")), |_| None);
+
+    // Running elsewhere first does not end the migration
+    fs::create_dir_all(root.join("elsewhere")).unwrap();
+    assert!(migrate_legacy(&root.join("elsewhere"), &locations).unwrap().is_empty());
+    assert!(!locations.data_dir.join(MIGRATED_MARKER).exists());
+
+    let moved = migrate_legacy(&legacy, &locations).unwrap();
+    assert_eq!(moved.len(), 3);
+    assert!(locations.config_file().exists());
+    assert!(locations.data_dir.join("prod.store").exists());
+    assert!(locations.data_dir.join("prod.backups").is_dir());
+    assert!(legacy.join("other.store").exists());
+    assert!(legacy.join("prod.java").exists());
+
+    // Only once
+    fs::write(legacy.join(CONFIG_FILE_NAME), "db=prod\n").unwrap();
+    fs::remove_file(locations.config_file()).unwrap();
+    assert!(migrate_legacy(&legacy, &locations).unwrap().is_empty());
+    assert!(legacy.join(CONFIG_FILE_NAME).exists());
+
+    // Nor an app.conf of another application
+    let other = Locations::resolve(Some(root.join("other")), |_| None);
+    fs::write(legacy.join(CONFIG_FILE_NAME), "port = 8080\n").unwrap();
+    assert!(migrate_legacy(&legacy, &other).unwrap().is_empty());
+    assert!(legacy.join(CONFIG_FILE_NAME).exists());
+    fs::remove_dir_all(&root).unwrap();
+}
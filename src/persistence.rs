@@ -1,13 +1,228 @@
 extern crate rusqlite;
 
-use rusqlite::NO_PARAMS;
-use rusqlite::{Connection, Result};
+use rusqlite::{params, NO_PARAMS};
+use rusqlite::{Connection, DatabaseName, OpenFlags, OptionalExtension, Result, Row, ToSql, TransactionBehavior};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
 
-use crate::domain::{Server, DEFAULT_USER};
+use log::info;
 
-pub fn init_db(db: &String) -> Result<Response> {
+use crate::domain::{Backend, Finding, Server, ServerQuery, SortField, User, DEFAULT_USER};
+use crate::json_store::JsonRepository;
+
+/// Current schema revision, tracked through `PRAGMA user_version`
+pub const SCHEMA_VERSION: i32 = 7;
+
+/// How long a connection waits on a lock held by another rusteze process
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SERVER_COLUMNS: &str = "s.id,s.title,s.domain,s.ip,s.user_name,u.name,s.alias,s.deleted_at,s.last_connected,s.notes,s.port";
+
+/// Storage backend the service layer runs its actions against
+pub trait Repository {
+    /// Creates the store on first setup, keeping the data of an existing one
+    fn initialize(&self) -> std::result::Result<Response, DbError>;
+    /// Runs `action` scoped to the servers of `owner`
+    fn execute(&self, action: CrudAction, owner: &str) -> std::result::Result<Response, DbError>;
+    /// Runs every action in one transaction, none of them applied when one fails
+    fn execute_all(&self, actions: Vec<CrudAction>, owner: &str) -> std::result::Result<Vec<Response>, DbError>;
+    /// Copies the whole store to `target`, which must not exist yet
+    fn backup(&self, target: &Path) -> std::result::Result<(), DbError>;
+    /// Checks that `source` is a backup `restore` accepts
+    fn verify_backup(&self, source: &Path) -> std::result::Result<(), DbError>;
+    /// Replaces the content of the store with a copy made by `backup`
+    fn restore(&self, source: &Path) -> std::result::Result<(), DbError>;
+}
+
+pub fn open_repository(backend: Backend, db: String) -> Box<dyn Repository> {
+    match backend {
+        Backend::Sqlite => Box::new(SqliteRepository { db }),
+        Backend::Json => Box::new(JsonRepository::new(db)),
+    }
+}
+
+/// The default backend, a SQLite database file
+pub struct SqliteRepository {
+    db: String,
+}
+
+thread_local! {
+    /// Connections opened by this invocation, kept open until it exits
+    static CONNECTIONS: RefCell<HashMap<String, Rc<RefCell<Connection>>>> = RefCell::new(HashMap::new());
+}
+
+impl SqliteRepository {
+    /// Connection to the db shared by every action of this invocation,
+    /// opened and migrated on first use
+    fn connection(&self) -> std::result::Result<Rc<RefCell<Connection>>, DbError> {
+        if let Some(conn) = CONNECTIONS.with(|connections| connections.borrow().get(&self.db).cloned()) {
+            return Ok(conn);
+        }
+        if !Path::new(&self.db).exists() {
+            return Err(DbError::NotInitialized(self.db.to_owned()));
+        }
+        info!("Opening the db {}", self.db);
+        let mut conn = open_connection(&self.db)?;
+        in_transaction(&mut conn, TransactionBehavior::Immediate, migrate)?;
+        let conn = Rc::new(RefCell::new(conn));
+        CONNECTIONS.with(|connections| {
+            connections.borrow_mut().insert(self.db.to_owned(), conn.clone())
+        });
+        Ok(conn)
+    }
+}
+
+impl Repository for SqliteRepository {
+    fn initialize(&self) -> std::result::Result<Response, DbError> {
+        Ok(init_db(&self.db)?)
+    }
+
+    fn execute(&self, action: CrudAction, owner: &str) -> std::result::Result<Response, DbError> {
+        let conn = self.connection()?;
+        let mut conn = conn.borrow_mut();
+        if let CrudAction::Vacuum = action {
+            // VACUUM cannot run inside a transaction
+            conn.execute_batch("VACUUM")?;
+            return Ok(Response::Success);
+        }
+        // Writes take the lock up front so a concurrent writer waits on the
+        // busy timeout instead of failing half way through
+        let behavior = if action.is_write() {
+            TransactionBehavior::Immediate
+        } else {
+            TransactionBehavior::Deferred
+        };
+        in_transaction(&mut conn, behavior, |conn| run(action, owner, conn))
+    }
+
+    fn execute_all(&self, actions: Vec<CrudAction>, owner: &str) -> std::result::Result<Vec<Response>, DbError> {
+        let conn = self.connection()?;
+        let mut conn = conn.borrow_mut();
+        in_transaction(&mut conn, TransactionBehavior::Immediate, |conn| {
+            actions.into_iter().map(|action| run(action, owner, conn)).collect()
+        })
+    }
+
+    /// Uses the online backup API, so other invocations can keep using the db
+    fn backup(&self, target: &Path) -> std::result::Result<(), DbError> {
+        if target.exists() {
+            return Err(DbError::Rejected(format!("{} already exists", target.display())));
+        }
+        let conn = self.connection()?;
+        let conn = conn.borrow();
+        conn.backup(DatabaseName::Main, target, None)?;
+        Ok(())
+    }
+
+    fn verify_backup(&self, source: &Path) -> std::result::Result<(), DbError> {
+        check_restorable(source)
+    }
+
+    fn restore(&self, source: &Path) -> std::result::Result<(), DbError> {
+        check_restorable(source)?;
+        let conn = self.connection()?;
+        let mut conn = conn.borrow_mut();
+        conn.restore(DatabaseName::Main, source, None::<fn(rusqlite::backup::Progress)>)?;
+        // Backups of older releases are brought up to date right away
+        in_transaction(&mut conn, TransactionBehavior::Immediate, migrate)?;
+        Ok(())
+    }
+}
+
+/// Refuses files that are not rusteze stores or were written by a newer
+/// release, whose schema this one would not understand
+fn check_restorable(source: &Path) -> std::result::Result<(), DbError> {
+    if !source.is_file() {
+        return Err(DbError::Rejected(format!("{} is not a file", source.display())));
+    }
+    let conn = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let tables: i64 = conn
+        .query_row(
+            "SELECT count(*) from sqlite_master where type = 'table' and name in ('server','user')",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .map_err(|_| DbError::Rejected(format!("{} is not a rusteze store", source.display())))?;
+    if tables != 2 {
+        return Err(DbError::Rejected(format!("{} is not a rusteze store", source.display())));
+    }
+    let version: i32 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(DbError::Rejected(format!(
+            "{} has schema version {}, this release supports up to {}",
+            source.display(),
+            version,
+            SCHEMA_VERSION
+        )));
+    }
+    Ok(())
+}
+
+fn run(action: CrudAction, owner: &str, conn: &Connection) -> std::result::Result<Response, DbError> {
+    Ok(match action {
+        CrudAction::Save(server) => insert_server(server, owner, conn)?,
+        CrudAction::Update(id, server) => update_server(id, server, owner, conn)?,
+        CrudAction::Find(id) => read_one(id, owner, conn)?,
+        CrudAction::FindByAlias(alias) => read_by_alias(&alias, owner, conn)?,
+        CrudAction::FindByName(name) => search_by_name(&name, owner, conn)?,
+        CrudAction::FindAll(query) => read_all(&query, owner, conn)?,
+        CrudAction::Touch(id) => touch_record(id, owner, conn)?,
+        CrudAction::Remove(id) => remove_record(id, owner, conn)?,
+        CrudAction::RemoveAll => remove_all_records(owner, conn)?,
+        CrudAction::FindDeleted => read_deleted(owner, conn)?,
+        CrudAction::Restore(id) => restore_record(id, owner, conn)?,
+        CrudAction::Purge(older_than) => purge_records(older_than, owner, conn)?,
+        CrudAction::SetAttributes(id, attributes) => set_attributes(id, &attributes, owner, conn)?,
+        CrudAction::RemoveAttributes(id, names) => remove_attributes(id, &names, owner, conn)?,
+        CrudAction::SetNotes(id, notes) => set_notes(id, notes, owner, conn)?,
+        CrudAction::SaveUser(name) => insert_user(&name, conn)?,
+        CrudAction::FindUsers => read_users(conn)?,
+        CrudAction::RemoveUser(name) => remove_user(&name, conn)?,
+        CrudAction::Diagnose => diagnose(conn)?,
+        CrudAction::Vacuum => Response::Success,
+    })
+}
+
+fn open_connection(db: &str) -> Result<Connection> {
     let conn = Connection::open(db)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // WAL lets readers carry on while another invocation writes
+    let _mode: String = conn.query_row("PRAGMA journal_mode=WAL", NO_PARAMS, |row| row.get(0))?;
+    // SQLite leaves the owner and server references unchecked otherwise
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    Ok(conn)
+}
 
+/// Runs `work` in one transaction, committed only when it succeeds
+fn in_transaction<T, E, F>(conn: &mut Connection, behavior: TransactionBehavior, work: F) -> std::result::Result<T, E>
+where
+    E: From<rusqlite::Error>,
+    F: FnOnce(&Connection) -> std::result::Result<T, E>,
+{
+    let tx = conn.transaction_with_behavior(behavior)?;
+    let result = work(&tx)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+fn init_db(db: &str) -> Result<Response> {
+    let mut conn = open_connection(db)?;
+    in_transaction(&mut conn, TransactionBehavior::Immediate, |conn| {
+        create_schema(conn)?;
+        if !user_exists(DEFAULT_USER, conn)? {
+            insert_user(DEFAULT_USER, conn)?;
+        }
+        Ok(Response::Success)
+    })
+}
+
+fn create_schema(conn: &Connection) -> Result<()> {
     conn.execute(
         "create table if not exists user (
              id integer primary key,
@@ -26,147 +241,892 @@ pub fn init_db(db: &String) -> Result<Response> {
          )",
         NO_PARAMS,
     )?;
+    migrate(conn)
+}
 
-    conn.execute(
-        "create table if not exists health (             
-             name text not null             
-         )",
-        NO_PARAMS,
-    )?;
+/// Brings stores created by older releases up to `SCHEMA_VERSION`
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: i32 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    if version < SCHEMA_VERSION {
+        info!("Migrating the db from schema {} to {}", version, SCHEMA_VERSION);
+    }
+    if version < 1 {
+        conn.execute("ALTER TABLE server ADD COLUMN alias text", NO_PARAMS)?;
+        conn.execute(
+            "create unique index if not exists server_alias_idx on server(alias)",
+            NO_PARAMS,
+        )?;
+    }
+    if version < 2 {
+        // Aliases only need to be unique within one owner's servers
+        conn.execute_batch(
+            "drop index if exists server_alias_idx;
+             create unique index if not exists server_owner_alias_idx on server(owner_id, alias);",
+        )?;
+    }
+    if version < 3 {
+        // Removed servers stay in the trash until purged; their aliases are free for reuse
+        conn.execute_batch(
+            "ALTER TABLE server ADD COLUMN deleted_at integer;
+             drop index if exists server_owner_alias_idx;
+             create unique index if not exists server_owner_alias_idx on server(owner_id, alias)
+                where deleted_at is null;",
+        )?;
+    }
+    if version < 4 {
+        conn.execute("ALTER TABLE server ADD COLUMN last_connected integer", NO_PARAMS)?;
+    }
+    if version < 5 {
+        conn.execute_batch(
+            "ALTER TABLE server ADD COLUMN notes text;
+             create table if not exists server_attribute (
+                 server_id integer not null references server(id),
+                 name text not null,
+                 value text not null,
+                 primary key (server_id, name)
+             );",
+        )?;
+    }
+    if version < 6 {
+        // Left over from the health probe that `doctor` replaced
+        conn.execute("drop table if exists health", NO_PARAMS)?;
+    }
+    if version < 7 {
+        conn.execute("ALTER TABLE server ADD COLUMN port integer", NO_PARAMS)?;
+    }
+    if version < SCHEMA_VERSION {
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+    }
+    Ok(())
+}
+
+/// Checks the db file and the data in it, see the `doctor` command
+fn diagnose(conn: &Connection) -> Result<Response> {
+    let restore = "Restore the latest backup with `rusteze db restore <file>`".to_owned();
+    let mut findings = Vec::new();
 
-    Ok(match insert_user(DEFAULT_USER, &conn) {
-        Ok(_) => Response::Success,
-        Err(_) => {
-            //Server : ignore unique constraint error
-            //println!("Init {}",e);
-            Response::Success
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let problems = stmt
+        .query_map(NO_PARAMS, |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    if problems.iter().all(|problem| problem == "ok") {
+        findings.push(Finding::ok("integrity", "Db file is consistent".to_owned()));
+    } else {
+        for problem in problems.iter().take(10) {
+            findings.push(Finding::error("integrity", problem.to_owned(), restore.to_owned()));
         }
-    })
-}
+    }
 
-pub fn check(conn: &Connection) -> Result<Response> {
-    #[derive(Debug)]
-    struct Health {
-        name: String,
+    let version: i32 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        findings.push(Finding::error(
+            "schema",
+            format!("Schema version {} is newer than {} supported by this release", version, SCHEMA_VERSION),
+            "Upgrade rusteze, or restore a backup made by this release".to_owned(),
+        ));
+    } else {
+        findings.push(Finding::ok("schema", format!("Schema version {}", version)));
     }
 
-    let name = String::from("health str");
-    conn.execute("INSERT INTO health (name) values (?1)", &[&name])?;
-    let mut stmt = conn.prepare("SELECT * FROM health;")?;
+    let mut stmt = conn.prepare(
+        "SELECT s.owner_id, count(*) from server s LEFT JOIN user u ON u.id = s.owner_id
+        where u.id is null GROUP BY s.owner_id",
+    )?;
+    let orphans = stmt
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(i64, i64)>>>()?;
+    let heir: Option<(i64, String)> = conn
+        .query_row("SELECT id, name FROM user ORDER BY id LIMIT 1", NO_PARAMS, |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .optional()?;
+    for (owner_id, count) in &orphans {
+        let fix = match &heir {
+            Some((heir_id, name)) => format!(
+                "Hand them to an existing user such as {}, e.g. sqlite3 <db> \"UPDATE server SET owner_id = {} where owner_id = {}\"",
+                name, heir_id, owner_id
+            ),
+            None => format!(
+                "Add a user with `rusteze user add <name>`, then hand them over with sqlite3 <db> \"UPDATE server SET owner_id = <its id> where owner_id = {}\"",
+                owner_id
+            ),
+        };
+        findings.push(Finding::error(
+            "owners",
+            format!("{} server(s) belong to the missing user id {}", count, owner_id),
+            fix,
+        ));
+    }
+    if orphans.is_empty() {
+        findings.push(Finding::ok("owners", "Every server has an owner".to_owned()));
+    }
 
-    let health = stmt.query_map(NO_PARAMS, |row| Ok(Health { name: row.get(0)? }))?;
-    conn.execute("DELETE FROM health", NO_PARAMS)?;
-    Ok(Response::Success)
+    // Servers without owners are reported above
+    let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+    let broken = stmt
+        .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(String, Option<i64>, String)>>>()?
+        .into_iter()
+        .filter(|(table, _, parent)| !(table == "server" && parent == "user"))
+        .collect::<Vec<_>>();
+    for (table, row, parent) in broken.iter().take(10) {
+        findings.push(Finding::warning(
+            "references",
+            format!("{} row {} points to a missing {}", table, row.unwrap_or_default(), parent),
+            format!("Delete the row, e.g. sqlite3 <db> \"DELETE FROM {} where rowid = {}\"", table, row.unwrap_or_default()),
+        ));
+    }
+    if broken.is_empty() {
+        findings.push(Finding::ok("references", "Every reference is valid".to_owned()));
+    }
+    Ok(Response::Report(findings))
 }
 
 pub enum CrudAction {
     Save(Server),
+    /// Replace the fields of a server, adding its attributes to the existing ones
+    Update(i64, Server),
     Find(i64),
+    FindByAlias(String),
+    FindByName(String),
     Remove(i64),
-    FindAll,
+    FindAll(ServerQuery),
+    Touch(i64),
     RemoveAll,
-    HealthCheck
+    FindDeleted,
+    Restore(i64),
+    Purge(Option<i64>),
+    SetAttributes(i64, Vec<(String, String)>),
+    RemoveAttributes(i64, Vec<String>),
+    SetNotes(i64, Option<String>),
+    SaveUser(String),
+    FindUsers,
+    RemoveUser(String),
+    Diagnose,
+    /// Rebuild the store to reclaim the space of deleted data
+    Vacuum,
+}
+impl CrudAction {
+    /// Whether running the action can change the store
+    pub fn is_write(&self) -> bool {
+        !matches!(
+            self,
+            CrudAction::Find(_)
+                | CrudAction::FindByAlias(_)
+                | CrudAction::FindByName(_)
+                | CrudAction::FindAll(_)
+                | CrudAction::FindDeleted
+                | CrudAction::FindUsers
+                | CrudAction::Diagnose
+        )
+    }
 }
+#[allow(clippy::large_enum_variant)]
 pub enum Response {
     List(Vec<Server>),
+    Users(Vec<User>),
     One(Option<Server>),
+    Purged(usize),
+    Report(Vec<Finding>),
     Success,
-    Error(String),
-}
-
-pub fn db_action(action: CrudAction, db: String) -> Response {
-    if let Ok(conn) = Connection::open(db) {
-        match action {
-            CrudAction::Save(server) => insert_server(server, &conn).unwrap(),
-            CrudAction::Find(id) => match read_one(id, &conn) {
-                Ok(resp) => resp,
-                Err(_) => Response::Error("Failure".to_string()),
-            },
-            CrudAction::FindAll => read_all(&conn).unwrap(),
-            CrudAction::Remove(id) => remove_record(id, &conn).unwrap(),
-            CrudAction::RemoveAll => remove_all_records(&conn).unwrap(),
-            CrudAction::HealthCheck => match check(&conn) {
-                Ok(resp) => resp,
-                Err(why) => {
-                    println!("{}", why);
-                    panic!("{}", why)
-                }
-            },
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    /// The store file does not exist yet, `init` has to be run first
+    NotInitialized(String),
+    /// The store refused the action, e.g. an unknown owner
+    Rejected(String),
+    Sqlite(rusqlite::Error),
+    Io(io::Error),
+    /// A file backed store could not be parsed
+    Format(serde_json::Error),
+}
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::NotInitialized(db) => write!(f, "Db store {} is not found", db),
+            DbError::Rejected(why) => write!(f, "{}", why),
+            DbError::Sqlite(why) => write!(f, "Db store error: {}", why),
+            DbError::Io(why) => write!(f, "Db store file error: {}", why),
+            DbError::Format(why) => write!(f, "Db store file is not valid: {}", why),
         }
-    } else {
-        println!("Db store is not found, Please setup application");
-        Response::Error("Db store is not found, Please setup application".to_string())
+    }
+}
+impl error::Error for DbError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DbError::Sqlite(why) => Some(why),
+            DbError::Io(why) => Some(why),
+            DbError::Format(why) => Some(why),
+            _ => None,
+        }
+    }
+}
+impl From<rusqlite::Error> for DbError {
+    fn from(why: rusqlite::Error) -> Self {
+        DbError::Sqlite(why)
+    }
+}
+impl From<io::Error> for DbError {
+    fn from(why: io::Error) -> Self {
+        DbError::Io(why)
+    }
+}
+impl From<serde_json::Error> for DbError {
+    fn from(why: serde_json::Error) -> Self {
+        DbError::Format(why)
     }
 }
 
 fn insert_user(name: &str, conn: &Connection) -> Result<Response> {
-    let last_id: String = conn.last_insert_rowid().to_string();
+    conn.execute("INSERT INTO user (name) values (?1)", &[&name])?;
+    Ok(Response::Success)
+}
+
+fn read_users(conn: &Connection) -> Result<Response> {
+    let mut stmt = conn.prepare(
+        "SELECT u.id,u.name,count(s.id) from user u
+        LEFT JOIN server s
+        ON s.owner_id = u.id and s.deleted_at is null GROUP BY u.id,u.name ORDER BY u.name",
+    )?;
+    let users = stmt
+        .query_map(NO_PARAMS, |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                servers: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<User>>>()?;
+    Ok(Response::Users(users))
+}
+
+/// Removes a user, refusing while they still own servers
+fn remove_user(name: &str, conn: &Connection) -> std::result::Result<Response, DbError> {
+    let owned: i64 = conn.query_row(
+        "SELECT count(s.id) from server s INNER JOIN user u ON u.id = s.owner_id where u.name = ?1",
+        &[&name],
+        |row| row.get(0),
+    )?;
+    if owned > 0 {
+        return Err(DbError::Rejected(format!(
+            "User {} still owns {} server(s) including the trash, remove and purge them first",
+            name, owned
+        )));
+    }
+    match conn.execute("DELETE FROM user where name = ?1", &[&name])? {
+        0 => Err(DbError::Rejected(format!("User {} does not exist", name))),
+        _ => Ok(Response::Success),
+    }
+}
+
+fn user_exists(name: &str, conn: &Connection) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT count(*) from user where name = ?1",
+        &[&name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn insert_server(
+    server: Server,
+    owner: &str,
+    conn: &Connection,
+) -> std::result::Result<Response, DbError> {
+    if !user_exists(owner, conn)? {
+        return Err(DbError::Rejected(format!(
+            "User {} does not exist, add it with `rusteze user add {}`",
+            owner, owner
+        )));
+    }
     conn.execute(
-        "INSERT INTO user (id,name) values (?1,?2)",
-        &[&last_id, &name.to_string()],
+        "INSERT INTO server (title,domain,ip,user_name,owner_id,alias,notes,port) values (?1,?2,?3,?4,(SELECT id FROM user where name = ?5),?6,?7,?8);",
+        params![
+            server.title,
+            server.domain.unwrap_or_default(),
+            server.ip.unwrap_or_default(),
+            server.user_name,
+            owner,
+            server.alias,
+            server.notes,
+            server.port
+        ],
     )?;
+    let id = conn.last_insert_rowid();
+    for (name, value) in &server.attributes {
+        conn.execute(
+            "INSERT INTO server_attribute (server_id,name,value) values (?1,?2,?3)",
+            params![id, name, value],
+        )?;
+    }
+
     Ok(Response::Success)
 }
 
-fn insert_server(server: Server, conn: &Connection) -> Result<Response> {
+fn server_from_row(row: &Row) -> Result<Server> {
+    Ok(Server {
+        id: Option::Some(row.get(0)?),
+        title: row.get(1)?,
+        domain: row.get(2)?,
+        ip: row.get(3)?,
+        user_name: row.get(4)?,
+        owner: row.get(5)?,
+        alias: row.get(6)?,
+        deleted_at: row.get(7)?,
+        last_connected: row.get(8)?,
+        attributes: BTreeMap::new(),
+        notes: row.get(9)?,
+        port: row.get(10)?,
+    })
+}
+
+/// Fills in the attributes of servers read through `server_from_row`
+fn with_attributes(mut servers: Vec<Server>, conn: &Connection) -> Result<Vec<Server>> {
+    let mut stmt = conn.prepare("SELECT name,value from server_attribute where server_id = ?1")?;
+    for server in servers.iter_mut() {
+        server.attributes = stmt
+            .query_map(&[&server.id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<BTreeMap<String, String>>>()?;
+    }
+    Ok(servers)
+}
+
+/// Whether `id` is a live server of `owner`
+fn is_live_server(id: i64, owner: &str, conn: &Connection) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT count(*) from server
+        where id = ?1 and owner_id = (SELECT id FROM user where name = ?2) and deleted_at is null",
+        params![id, owner],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn update_server(id: i64, server: Server, owner: &str, conn: &Connection) -> Result<Response> {
+    if !is_live_server(id, owner, conn)? {
+        return Ok(Response::One(None));
+    }
     conn.execute(
-        "INSERT INTO server (title,domain,ip,user_name,owner_id) values (?1,?2,?3,?4,(SELECT id FROM user where name = ?5));",
-        &[&server.title.to_string(),&server.domain.unwrap_or("".to_owned()),&server.ip.unwrap_or("".to_owned()),&server.user_name, &DEFAULT_USER.to_string()],
+        "UPDATE server SET title = ?1, alias = ?2, domain = ?3, ip = ?4, port = ?5, user_name = ?6, notes = ?7
+        where id = ?8",
+        params![
+            server.title,
+            server.alias,
+            server.domain.unwrap_or_default(),
+            server.ip.unwrap_or_default(),
+            server.port,
+            server.user_name,
+            server.notes,
+            id
+        ],
     )?;
+    let attributes: Vec<(String, String)> = server.attributes.into_iter().collect();
+    set_attributes(id, &attributes, owner, conn)
+}
 
+fn set_attributes(id: i64, attributes: &[(String, String)], owner: &str, conn: &Connection) -> Result<Response> {
+    if !is_live_server(id, owner, conn)? {
+        return Ok(Response::One(None));
+    }
+    for (name, value) in attributes {
+        conn.execute(
+            "INSERT OR REPLACE INTO server_attribute (server_id,name,value) values (?1,?2,?3)",
+            params![id, name, value],
+        )?;
+    }
     Ok(Response::Success)
 }
-fn read_one(id: i64, conn: &Connection) -> Result<Response> {
-    let mut stmt = conn.prepare(
-        "SELECT s.id,s.title,s.domain,s.ip,s.user_name,u.name from server s
-        INNER JOIN user u
-        ON u.id = s.owner_id where s.id = :id and u.id = (SELECT id FROM user where name = :name)",
-    )?;
 
-    let mut rows = stmt.query_named(&[(":id", &id), (":name", &DEFAULT_USER)])?;
-    let mut result: Option<Server> = None;
-    while let Some(row) = rows.next()? {
-        result = Some(Server {
-            id: Option::Some(row.get(0)?),
-            title: row.get(1)?,
-            domain: row.get(2)?,
-            ip: row.get(3)?,
-            user_name: row.get(4)?,
-            owner: row.get(5)?,
-        })
+fn remove_attributes(id: i64, names: &[String], owner: &str, conn: &Connection) -> Result<Response> {
+    if !is_live_server(id, owner, conn)? {
+        return Ok(Response::One(None));
+    }
+    for name in names {
+        conn.execute(
+            "DELETE FROM server_attribute where server_id = ?1 and name = ?2",
+            params![id, name],
+        )?;
     }
-    Ok(Response::One(result))
+    Ok(Response::Success)
 }
 
-fn read_all(conn: &Connection) -> Result<Response> {
-    let mut stmt = conn.prepare(
-        "SELECT s.id,s.title,s.domain,s.ip,s.user_name,u.name from server s
+fn set_notes(id: i64, notes: Option<String>, owner: &str, conn: &Connection) -> Result<Response> {
+    if !is_live_server(id, owner, conn)? {
+        return Ok(Response::One(None));
+    }
+    conn.execute("UPDATE server SET notes = ?1 where id = ?2", params![notes, id])?;
+    Ok(Response::Success)
+}
+
+fn read_one(id: i64, owner: &str, conn: &Connection) -> Result<Response> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} from server s
         INNER JOIN user u
-        ON u.id = s.owner_id;",
-    )?;
-    let servers = stmt.query_map(NO_PARAMS, |row| {
-        Ok(Server {
-            id: Option::Some(row.get(0)?),
-            title: row.get(1)?,
-            domain: Some(row.get(2)?),
-            ip: Some(row.get(3)?),
-            user_name: row.get(4)?,
-            owner: row.get(5)?,
+        ON u.id = s.owner_id where s.id = :id and u.id = (SELECT id FROM user where name = :name)
+        and s.deleted_at is null",
+        SERVER_COLUMNS
+    ))?;
+
+    let servers = stmt
+        .query_map_named(&[(":id", &id), (":name", &owner)], server_from_row)?
+        .collect::<Result<Vec<Server>>>()?;
+    Ok(Response::One(with_attributes(servers, conn)?.pop()))
+}
+
+fn read_by_alias(alias: &str, owner: &str, conn: &Connection) -> Result<Response> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} from server s
+        INNER JOIN user u
+        ON u.id = s.owner_id where s.alias = :alias and u.name = :name and s.deleted_at is null",
+        SERVER_COLUMNS
+    ))?;
+
+    let servers = stmt
+        .query_map_named(&[(":alias", &alias), (":name", &owner)], server_from_row)?
+        .collect::<Result<Vec<Server>>>()?;
+    Ok(Response::One(with_attributes(servers, conn)?.pop()))
+}
+
+/// Escapes LIKE wildcards, for patterns declared with `ESCAPE '\'`
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Servers whose title matches `name` exactly, or failing that, whose title
+/// or alias starts with `name` (case-insensitive)
+fn search_by_name(name: &str, owner: &str, conn: &Connection) -> Result<Response> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} from server s
+        INNER JOIN user u
+        ON u.id = s.owner_id where s.title = :title and u.name = :name and s.deleted_at is null
+        ORDER BY s.id",
+        SERVER_COLUMNS
+    ))?;
+    let exact = stmt
+        .query_map_named(&[(":title", &name), (":name", &owner)], server_from_row)?
+        .collect::<Result<Vec<Server>>>()?;
+    if !exact.is_empty() {
+        return Ok(Response::List(with_attributes(exact, conn)?));
+    }
+
+    let prefix = format!("{}%", escape_like(name));
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} from server s
+        INNER JOIN user u
+        ON u.id = s.owner_id
+        where (s.title LIKE :prefix ESCAPE '\\' OR s.alias LIKE :prefix ESCAPE '\\') and u.name = :name
+        and s.deleted_at is null ORDER BY s.id",
+        SERVER_COLUMNS
+    ))?;
+    let candidates = stmt
+        .query_map_named(&[(":prefix", &prefix), (":name", &owner)], server_from_row)?
+        .collect::<Result<Vec<Server>>>()?;
+    Ok(Response::List(with_attributes(candidates, conn)?))
+}
+
+/// Lists the live servers of `owner`, with the filters, ordering and paging
+/// of `query` pushed down into the statement
+fn read_all(query: &ServerQuery, owner: &str, conn: &Connection) -> Result<Response> {
+    let mut conditions = vec!["u.name = :name", "s.deleted_at is null"];
+    let mut params: Vec<(&str, &dyn ToSql)> = vec![(":name", &owner)];
+
+    let attribute_names: Vec<(String, String)> = (0..query.attributes.len())
+        .map(|index| (format!(":attr{}", index), format!(":value{}", index)))
+        .collect();
+    let attribute_conditions: Vec<String> = query
+        .attributes
+        .iter()
+        .zip(attribute_names.iter())
+        .map(|((_, value), (name_param, value_param))| {
+            let value_condition = match value {
+                Some(_) => format!(" and a.value = {}", value_param),
+                None => String::new(),
+            };
+            format!(
+                "exists (SELECT 1 from server_attribute a where a.server_id = s.id and a.name = {}{})",
+                name_param, value_condition
+            )
         })
-    })?;
-    let collected: rusqlite::Result<Vec<Server>> = servers.collect();
-    let result = match collected {
-        Ok(list) => list,
-        Err(_) => Vec::<Server>::new(),
+        .collect();
+    for (((name, value), (name_param, value_param)), condition) in query
+        .attributes
+        .iter()
+        .zip(attribute_names.iter())
+        .zip(attribute_conditions.iter())
+    {
+        conditions.push(condition);
+        params.push((name_param, name));
+        if let Some(value) = value {
+            params.push((value_param, value));
+        }
+    }
+
+    if let Some(user_name) = &query.user_name {
+        conditions.push("s.user_name = :user_name");
+        params.push((":user_name", user_name));
+    }
+    let domain_pattern = query
+        .domain_suffix
+        .as_ref()
+        .map(|suffix| format!("%{}", escape_like(suffix)));
+    if let Some(pattern) = &domain_pattern {
+        conditions.push("s.domain LIKE :domain ESCAPE '\\'");
+        params.push((":domain", pattern));
+    }
+    match query.has_ip {
+        Some(true) => conditions.push("coalesce(s.ip,'') != ''"),
+        Some(false) => conditions.push("coalesce(s.ip,'') = ''"),
+        None => (),
+    }
+
+    let column = match query.sort {
+        SortField::Title => "s.title COLLATE NOCASE",
+        SortField::Ip => "s.ip",
+        SortField::Created => "s.id",
+        SortField::LastConnected => "s.last_connected",
     };
-    Ok(Response::List(result))
+    let direction = if query.descending { "DESC" } else { "ASC" };
+    let limit = query.limit.map(i64::from).unwrap_or(-1);
+    let offset = i64::from(query.offset.unwrap_or(0));
+    params.push((":limit", &limit));
+    params.push((":offset", &offset));
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} from server s
+        INNER JOIN user u
+        ON u.id = s.owner_id where {}
+        ORDER BY {} {}, s.id {} LIMIT :limit OFFSET :offset",
+        SERVER_COLUMNS,
+        conditions.join(" and "),
+        column,
+        direction,
+        direction
+    ))?;
+    let servers = stmt
+        .query_map_named(&params, server_from_row)?
+        .collect::<Result<Vec<Server>>>()?;
+    Ok(Response::List(with_attributes(servers, conn)?))
 }
 
-fn remove_all_records(conn: &Connection) -> Result<Response> {
-    conn.execute("DELETE FROM Server", NO_PARAMS)?;
+fn touch_record(id: i64, owner: &str, conn: &Connection) -> Result<Response> {
+    conn.execute(
+        "UPDATE server SET last_connected = strftime('%s','now')
+        where id = ?1 and owner_id = (SELECT id FROM user where name = ?2)",
+        params![id, owner],
+    )?;
     Ok(Response::Success)
 }
-fn remove_record(id: i64, conn: &Connection) -> Result<Response> {
-    conn.execute("DELETE FROM Server where id =?", &[&id])?;
+
+fn remove_all_records(owner: &str, conn: &Connection) -> Result<Response> {
+    conn.execute(
+        "UPDATE server SET deleted_at = strftime('%s','now')
+        where owner_id = (SELECT id FROM user where name = ?1) and deleted_at is null",
+        &[&owner],
+    )?;
     Ok(Response::Success)
 }
+fn remove_record(id: i64, owner: &str, conn: &Connection) -> Result<Response> {
+    let removed = conn.execute(
+        "UPDATE server SET deleted_at = strftime('%s','now')
+        where id = ?1 and owner_id = (SELECT id FROM user where name = ?2) and deleted_at is null",
+        params![id, owner],
+    )?;
+    Ok(match removed {
+        0 => Response::One(None),
+        _ => Response::Success,
+    })
+}
+
+fn read_deleted(owner: &str, conn: &Connection) -> Result<Response> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} from server s
+        INNER JOIN user u
+        ON u.id = s.owner_id where u.name = :name and s.deleted_at is not null
+        ORDER BY s.deleted_at DESC, s.id",
+        SERVER_COLUMNS
+    ))?;
+    let servers = stmt
+        .query_map_named(&[(":name", &owner)], server_from_row)?
+        .collect::<Result<Vec<Server>>>()?;
+    Ok(Response::List(with_attributes(servers, conn)?))
+}
+
+fn restore_record(id: i64, owner: &str, conn: &Connection) -> Result<Response> {
+    let restored = conn.execute(
+        "UPDATE server SET deleted_at = null
+        where id = ?1 and owner_id = (SELECT id FROM user where name = ?2) and deleted_at is not null",
+        params![id, owner],
+    )?;
+    Ok(match restored {
+        0 => Response::One(None),
+        _ => Response::Success,
+    })
+}
+
+/// Permanently deletes trashed servers, only those removed more than
+/// `older_than` seconds ago when given
+fn purge_records(older_than: Option<i64>, owner: &str, conn: &Connection) -> Result<Response> {
+    conn.execute(
+        "DELETE FROM server_attribute where server_id in (SELECT id FROM server
+        where owner_id = (SELECT id FROM user where name = ?1) and deleted_at is not null
+        and deleted_at <= strftime('%s','now') - ?2)",
+        params![owner, older_than.unwrap_or(0)],
+    )?;
+    let purged = conn.execute(
+        "DELETE FROM server
+        where owner_id = (SELECT id FROM user where name = ?1) and deleted_at is not null
+        and deleted_at <= strftime('%s','now') - ?2",
+        params![owner, older_than.unwrap_or(0)],
+    )?;
+    Ok(Response::Purged(purged))
+}
+
+#[cfg(test)]
+fn test_connection() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    create_schema(&conn).unwrap();
+    insert_user(DEFAULT_USER, &conn).unwrap();
+    conn
+}
+
+#[cfg(test)]
+fn test_server(title: &str, alias: Option<&str>) -> Server {
+    Server {
+        id: None,
+        title: title.to_owned(),
+        alias: alias.map(str::to_owned),
+        domain: Some(format!("{}.example.com", title)),
+        ip: None,
+        port: None,
+        user_name: "admin".to_owned(),
+        owner: None,
+        last_connected: None,
+        deleted_at: None,
+        attributes: BTreeMap::new(),
+        notes: None,
+    }
+}
+
+#[test]
+fn search_by_name_test() {
+    let conn = test_connection();
+    insert_server(test_server("web-1", Some("w1")), DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("web-2", None), DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("db", None), DEFAULT_USER, &conn).unwrap();
+
+    let found = |name: &str| match search_by_name(name, DEFAULT_USER, &conn).unwrap() {
+        Response::List(servers) => servers.into_iter().map(|s| s.title).collect::<Vec<_>>(),
+        _ => panic!("expected a list"),
+    };
+    assert_eq!(found("db"), vec!["db"]);
+    assert_eq!(found("WEB"), vec!["web-1", "web-2"]);
+    assert_eq!(found("w1"), vec!["web-1"]);
+    assert!(found("web_").is_empty());
+    assert!(matches!(read_by_alias("w1", DEFAULT_USER, &conn), Ok(Response::One(Some(_)))));
+}
+
+#[test]
+fn duplicate_alias_test() {
+    let conn = test_connection();
+    insert_server(test_server("web-1", Some("web")), DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("web-2", None), DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("web-3", None), DEFAULT_USER, &conn).unwrap();
+    assert!(insert_server(test_server("web-4", Some("web")), DEFAULT_USER, &conn).is_err());
+}
+
+#[test]
+fn owner_scope_test() {
+    let conn = test_connection();
+    insert_user("alice", &conn).unwrap();
+    insert_server(test_server("web", Some("web")), DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("web", Some("web")), "alice", &conn).unwrap();
+    assert!(matches!(
+        insert_server(test_server("db", None), "bob", &conn),
+        Err(DbError::Rejected(_))
+    ));
+
+    remove_all_records(DEFAULT_USER, &conn).unwrap();
+    match read_all(&ServerQuery::default(), "alice", &conn).unwrap() {
+        Response::List(servers) => assert_eq!(servers.len(), 1),
+        _ => panic!("expected a list"),
+    }
+    assert!(matches!(read_all(&ServerQuery::default(), DEFAULT_USER, &conn), Ok(Response::List(ref s)) if s.is_empty()));
+    assert!(matches!(remove_user("alice", &conn), Err(DbError::Rejected(_))));
+}
+
+#[test]
+fn trash_test() {
+    let conn = test_connection();
+    insert_server(test_server("web", Some("web")), DEFAULT_USER, &conn).unwrap();
+    remove_all_records(DEFAULT_USER, &conn).unwrap();
+    assert!(matches!(read_all(&ServerQuery::default(), DEFAULT_USER, &conn), Ok(Response::List(ref s)) if s.is_empty()));
+    assert!(matches!(read_one(1, DEFAULT_USER, &conn), Ok(Response::One(None))));
+
+    // the alias of a trashed server can be reused, but then blocks its restore
+    insert_server(test_server("web", Some("web")), DEFAULT_USER, &conn).unwrap();
+    assert!(restore_record(1, DEFAULT_USER, &conn).is_err());
+    remove_record(2, DEFAULT_USER, &conn).unwrap();
+    assert!(matches!(remove_record(2, DEFAULT_USER, &conn), Ok(Response::One(None))));
+    assert!(matches!(remove_record(1, "bob", &conn), Ok(Response::One(None))));
+    assert!(matches!(restore_record(1, DEFAULT_USER, &conn), Ok(Response::Success)));
+    assert!(matches!(read_deleted(DEFAULT_USER, &conn), Ok(Response::List(ref s)) if s.len() == 1));
+
+    assert!(matches!(purge_records(Some(3600), DEFAULT_USER, &conn), Ok(Response::Purged(0))));
+    assert!(matches!(purge_records(None, DEFAULT_USER, &conn), Ok(Response::Purged(1))));
+}
+
+#[test]
+fn read_all_query_test() {
+    let conn = test_connection();
+    let mut server = test_server("b", None);
+    server.ip = Some("10.0.0.2".to_owned());
+    insert_server(server, DEFAULT_USER, &conn).unwrap();
+    let mut server = test_server("a", None);
+    server.user_name = "deploy".to_owned();
+    insert_server(server, DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("c", None), DEFAULT_USER, &conn).unwrap();
+    touch_record(1, DEFAULT_USER, &conn).unwrap();
+
+    let titles = |query: ServerQuery| match read_all(&query, DEFAULT_USER, &conn).unwrap() {
+        Response::List(servers) => servers.into_iter().map(|s| s.title).collect::<Vec<_>>(),
+        _ => panic!("expected a list"),
+    };
+    let sorted = |sort: SortField, descending: bool| ServerQuery {
+        sort,
+        descending,
+        ..ServerQuery::default()
+    };
+    assert_eq!(titles(ServerQuery::default()), vec!["b", "a", "c"]);
+    assert_eq!(titles(sorted(SortField::Title, true)), vec!["c", "b", "a"]);
+    assert_eq!(titles(sorted(SortField::LastConnected, true)), vec!["b", "c", "a"]);
+    assert_eq!(
+        titles(ServerQuery { limit: Some(1), offset: Some(1), ..sorted(SortField::Title, false) }),
+        vec!["b"]
+    );
+    assert_eq!(
+        titles(ServerQuery { user_name: Some("deploy".to_owned()), ..ServerQuery::default() }),
+        vec!["a"]
+    );
+    assert_eq!(
+        titles(ServerQuery { domain_suffix: Some("c.example.com".to_owned()), ..ServerQuery::default() }),
+        vec!["c"]
+    );
+    assert_eq!(titles(ServerQuery { has_ip: Some(true), ..ServerQuery::default() }), vec!["b"]);
+}
+
+#[test]
+fn transaction_rollback_test() {
+    let mut conn = test_connection();
+    let failed: std::result::Result<Response, DbError> =
+        in_transaction(&mut conn, TransactionBehavior::Immediate, |conn| {
+            insert_server(test_server("web-1", Some("web")), DEFAULT_USER, conn)?;
+            insert_server(test_server("web-2", Some("web")), DEFAULT_USER, conn)
+        });
+    assert!(failed.is_err());
+    match read_all(&ServerQuery::default(), DEFAULT_USER, &conn).unwrap() {
+        Response::List(servers) => assert!(servers.is_empty()),
+        _ => panic!("expected a list"),
+    }
+
+    insert_server(test_server("web-1", Some("web")), DEFAULT_USER, &conn).unwrap();
+    let mut renamed = test_server("web-one", Some("one"));
+    renamed.port = Some(2222);
+    update_server(1, renamed, DEFAULT_USER, &conn).unwrap();
+    match read_one(1, DEFAULT_USER, &conn).unwrap() {
+        Response::One(Some(server)) => {
+            assert_eq!(server.title, "web-one");
+            assert_eq!(server.port, Some(2222));
+        }
+        _ => panic!("expected the updated server"),
+    }
+}
+
+#[test]
+fn attributes_test() {
+    let conn = test_connection();
+    let mut tagged = test_server("web-1", None);
+    tagged.attributes.insert("dc".to_owned(), "ams".to_owned());
+    insert_server(tagged, DEFAULT_USER, &conn).unwrap();
+    insert_server(test_server("web-2", None), DEFAULT_USER, &conn).unwrap();
+    set_attributes(2, &[("dc".to_owned(), "fra".to_owned()), ("os".to_owned(), "bsd".to_owned())], DEFAULT_USER, &conn).unwrap();
+    remove_attributes(2, &["os".to_owned()], DEFAULT_USER, &conn).unwrap();
+    set_notes(2, Some("# Runbook".to_owned()), DEFAULT_USER, &conn).unwrap();
+    assert!(matches!(set_notes(9, None, DEFAULT_USER, &conn), Ok(Response::One(None))));
+
+    let titles = |attributes: Vec<(String, Option<String>)>| {
+        let query = ServerQuery {
+            attributes,
+            ..ServerQuery::default()
+        };
+        match read_all(&query, DEFAULT_USER, &conn).unwrap() {
+            Response::List(servers) => servers.into_iter().map(|s| s.title).collect::<Vec<_>>(),
+            _ => panic!("expected a list"),
+        }
+    };
+    assert_eq!(titles(vec![("dc".to_owned(), None)]), vec!["web-1", "web-2"]);
+    assert_eq!(titles(vec![("dc".to_owned(), Some("fra".to_owned()))]), vec!["web-2"]);
+    assert!(titles(vec![("os".to_owned(), None)]).is_empty());
+
+    match read_one(2, DEFAULT_USER, &conn).unwrap() {
+        Response::One(Some(server)) => {
+            assert_eq!(server.attributes.get("dc").map(String::as_str), Some("fra"));
+            assert_eq!(server.notes.as_deref(), Some("# Runbook"));
+        }
+        _ => panic!("expected the server"),
+    }
+
+    remove_record(1, DEFAULT_USER, &conn).unwrap();
+    purge_records(None, DEFAULT_USER, &conn).unwrap();
+    let left: i64 = conn
+        .query_row("SELECT count(*) from server_attribute", NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    assert_eq!(left, 1);
+}
+
+#[test]
+fn foreign_keys_test() {
+    let conn = open_connection(":memory:").unwrap();
+    create_schema(&conn).unwrap();
+    insert_user(DEFAULT_USER, &conn).unwrap();
+    let lost = "INSERT INTO server (title,user_name,owner_id) values ('lost','admin',42)";
+    assert!(conn.execute(lost, NO_PARAMS).is_err());
+    insert_server(test_server("web-1", None), DEFAULT_USER, &conn).unwrap();
+    let orphan = "INSERT INTO server_attribute (server_id,name,value) values (99,'dc','ams')";
+    assert!(conn.execute(orphan, NO_PARAMS).is_err());
+}
+
+#[test]
+fn diagnose_test() {
+    let conn = test_connection();
+    let problems = |conn: &Connection| match diagnose(conn).unwrap() {
+        Response::Report(findings) => findings
+            .into_iter()
+            .filter(|finding| finding.severity != crate::domain::Severity::Ok)
+            .map(|finding| finding.check)
+            .collect::<Vec<String>>(),
+        _ => panic!("expected a report"),
+    };
+    insert_server(test_server("web-1", None), DEFAULT_USER, &conn).unwrap();
+    assert!(problems(&conn).is_empty());
+
+    conn.execute_batch(
+        "INSERT INTO server (title,user_name,owner_id) values ('lost','admin',42);
+         INSERT INTO server_attribute (server_id,name,value) values (99,'dc','ams');",
+    )
+    .unwrap();
+    assert_eq!(problems(&conn), vec!["owners", "references"]);
+    let root_id: i64 = conn.query_row("SELECT id FROM user where name = 'root'", NO_PARAMS, |row| row.get(0)).unwrap();
+    match diagnose(&conn).unwrap() {
+        Response::Report(findings) => {
+            let owners = findings.iter().find(|finding| finding.check == "owners").unwrap();
+            let suggestion = owners.suggestion.as_deref().unwrap_or_default();
+            assert!(suggestion.contains(&format!("SET owner_id = {} where owner_id = 42", root_id)));
+        }
+        _ => panic!("expected a report"),
+    }
+}
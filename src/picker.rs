@@ -0,0 +1,263 @@
+use std::io;
+
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use rusteze::domain::Server;
+
+/// Servers narrowed down by a fuzzy query, the selection moving over the
+/// matches only
+pub struct Picker {
+    servers: Vec<Server>,
+    label: fn(&Server) -> String,
+    labels: Vec<String>,
+    query: String,
+    /// Index of the server and positions of the matched characters in its
+    /// label, best match first
+    matches: Vec<(usize, Vec<usize>)>,
+    selected: usize,
+    matcher: SkimMatcherV2,
+}
+
+impl Picker {
+    pub fn new(servers: Vec<Server>, label: fn(&Server) -> String) -> Picker {
+        let labels = servers.iter().map(label).collect();
+        let mut picker = Picker {
+            servers,
+            label,
+            labels,
+            query: String::new(),
+            matches: Vec::new(),
+            selected: 0,
+            matcher: SkimMatcherV2::default().ignore_case(),
+        };
+        picker.filter();
+        picker
+    }
+
+    fn filter(&mut self) {
+        let mut matches = self
+            .labels
+            .iter()
+            .enumerate()
+            .filter_map(|(index, label)| {
+                self.matcher
+                    .fuzzy_indices(label, &self.query)
+                    .map(|(score, positions)| (score, index, positions))
+            })
+            .collect::<Vec<_>>();
+        // Stable, so equal scores keep the order the servers came in
+        matches.sort_by_key(|(score, _, _)| std::cmp::Reverse(*score));
+        self.matches = matches.into_iter().map(|(_, index, positions)| (index, positions)).collect();
+        self.selected = 0;
+    }
+
+    /// Replaces the servers, keeping the query and the selected server when
+    /// it is still there
+    pub fn reload(&mut self, servers: Vec<Server>) {
+        let selected = self.selected().and_then(|server| server.id);
+        self.labels = servers.iter().map(self.label).collect();
+        self.servers = servers;
+        self.filter();
+        if let Some(position) = self
+            .matches
+            .iter()
+            .position(|(index, _)| selected.is_some() && self.servers[*index].id == selected)
+        {
+            self.selected = position;
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn push(&mut self, character: char) {
+        self.query.push(character);
+        self.filter();
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.filter();
+    }
+
+    pub fn clear(&mut self) {
+        self.query.clear();
+        self.filter();
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn selected(&self) -> Option<&Server> {
+        self.matches.get(self.selected).map(|(index, _)| &self.servers[*index])
+    }
+
+    fn draw(&self, frame: &mut Frame, preview: &dyn Fn(&Server) -> String) {
+        let [prompt, body, help] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [list_area, preview_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+
+        self.draw_prompt(frame, prompt);
+        self.draw_list(frame, list_area);
+        let details = self.selected().map(preview).unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(details)
+                .block(Block::bordered().title(" Details "))
+                .wrap(Wrap { trim: false }),
+            preview_area,
+        );
+        frame.render_widget(
+            Line::from("type to filter, up/down to move, enter to connect, esc to cancel").dim(),
+            help,
+        );
+    }
+
+    /// The query and how many servers match it
+    pub fn draw_prompt(&self, frame: &mut Frame, area: Rect) {
+        frame.render_widget(
+            Line::from(vec![
+                Span::from("> ").bold(),
+                Span::from(self.query.as_str()),
+                Span::from(format!("  {}/{}", self.matches.len(), self.servers.len())).dim(),
+            ]),
+            area,
+        );
+    }
+
+    /// The matching servers, matched characters highlighted
+    pub fn draw_list(&self, frame: &mut Frame, area: Rect) {
+        let items = self.matches.iter().map(|(index, positions)| {
+            let spans = self.labels[*index]
+                .chars()
+                .enumerate()
+                .map(|(position, character)| {
+                    let span = Span::from(character.to_string());
+                    if positions.contains(&position) {
+                        span.yellow().bold()
+                    } else {
+                        span
+                    }
+                })
+                .collect::<Vec<_>>();
+            ListItem::new(Line::from(spans))
+        });
+        let list = List::new(items)
+            .block(Block::bordered().title(" Servers "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        let mut state = ListState::default().with_selected(self.selected().map(|_| self.selected));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    /// Applies a key press, returning whether the picker is done
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Choice> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => return Some(Choice::Selected),
+            KeyCode::Esc => return Some(Choice::Cancelled),
+            KeyCode::Char('c') if control => return Some(Choice::Cancelled),
+            KeyCode::Up => self.up(),
+            KeyCode::Char('p') | KeyCode::Char('k') if control => self.up(),
+            KeyCode::Down => self.down(),
+            KeyCode::Char('n') | KeyCode::Char('j') if control => self.down(),
+            KeyCode::Char('u') if control => self.clear(),
+            KeyCode::Backspace => self.pop(),
+            KeyCode::Char(character) if !control => self.push(character),
+            _ => (),
+        }
+        None
+    }
+}
+
+enum Choice {
+    Selected,
+    Cancelled,
+}
+
+/// Lets the user pick one of `servers` on the full terminal, `None` when
+/// cancelled or when nothing matches
+pub fn pick(
+    servers: Vec<Server>,
+    label: fn(&Server) -> String,
+    preview: impl Fn(&Server) -> String,
+) -> io::Result<Option<Server>> {
+    let mut picker = Picker::new(servers, label);
+    let mut terminal = ratatui::try_init()?;
+    let choice = run(&mut terminal, &mut picker, &preview);
+    ratatui::try_restore()?;
+    match choice? {
+        Choice::Selected => Ok(picker.selected().cloned()),
+        Choice::Cancelled => Ok(None),
+    }
+}
+
+fn run(terminal: &mut DefaultTerminal, picker: &mut Picker, preview: &dyn Fn(&Server) -> String) -> io::Result<Choice> {
+    loop {
+        terminal.draw(|frame| picker.draw(frame, preview))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if let Some(choice) = picker.handle_key(key) {
+                return Ok(choice);
+            }
+        }
+    }
+}
+
+#[test]
+fn picker_filter_test() {
+    let server = |id: i32, title: &str, domain: &str| Server {
+        id: Some(id),
+        ..Server::new(title.to_owned(), Some(domain.to_owned()), None, "admin".to_owned())
+    };
+    let servers = vec![
+        server(1, "web", "web.example.com"),
+        server(2, "database", "db.example.com"),
+        server(3, "web-staging", "staging.example.com"),
+    ];
+    let mut picker = Picker::new(servers, |server| format!("{} {}", server.title, server.domain.clone().unwrap_or_default()));
+    picker.down();
+    picker.reload(picker.servers.iter().rev().cloned().collect());
+    assert_eq!(picker.selected().and_then(|server| server.id), Some(2));
+    picker.reload(picker.servers.iter().rev().cloned().collect());
+    picker.up();
+    assert_eq!(picker.matches.len(), 3);
+    assert_eq!(picker.selected().and_then(|server| server.id), Some(1));
+
+    for character in "stg".chars() {
+        picker.push(character);
+    }
+    assert_eq!(picker.matches.len(), 1);
+    assert_eq!(picker.selected().and_then(|server| server.id), Some(3));
+
+    picker.clear();
+    picker.push('D');
+    picker.down();
+    picker.down();
+    picker.down();
+    assert_eq!(picker.selected, picker.matches.len() - 1);
+    picker.handle_key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE));
+    assert_eq!(picker.query, "Dq");
+    assert!(picker.selected().is_none());
+    assert!(matches!(
+        picker.handle_key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)),
+        Some(Choice::Cancelled)
+    ));
+}
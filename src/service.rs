@@ -1,7 +1,12 @@
-use crate::domain::{Action, Server, ActionErr, ActionErrType, ActionResponse, ID};
-use crate::persistence::{db_action, CrudAction, Response};
+use crate::domain::{
+    Action, Server, ActionErr, ActionErrType, ActionResponse, ConflictPolicy, Finding, ImportSummary,
+    ServerQuery, validate_server, ID,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::persistence::{CrudAction, DbError, Repository, Response};
 use cfg_if::*;
-use crate::connector::connect_terminal;
+use crate::connector::{self, Launch};
+use crate::vault::{self, VaultError};
 use log::info;
 
 cfg_if! {
@@ -13,92 +18,517 @@ cfg_if! {
 }
 
 pub fn action_router(configuration: &Settings, action: Action) -> Result<ActionResponse, ActionErr> {
-    let db = configuration.get_db();
     if configuration.is_config_available() {
+        let repository = configuration.open_repository();
         match action {
-            Action::Save(server) => save(server, db),
-            Action::Fetch => fetch(db),
-            Action::FetchById(id) => fetch_by_id(id, db),
-            Action::Delete => delete(db),
-            Action::DeleteById(id) => delete_by_id(id, db),
-            Action::Connect(id) => connect(id,db)
+            Action::PurgeTrash(older_than) => purge_with_secrets(
+                older_than,
+                repository.as_ref(),
+                &configuration.get_user(),
+                &configuration.get_vault(),
+            ),
+            action => route(repository.as_ref(), &configuration.get_user(), action),
         }
     } else {
         Err(ActionErr::build(ActionErrType::InitNotAvailable))
     }
 }
-fn connect(id: ID, db: String) -> Result<ActionResponse,ActionErr> {
-    if let Ok(response) = fetch_by_id(id,db){
-        let error = Err(ActionErr::build(ActionErrType::ActionFailed("Connect Action failed".to_owned()))); 
-        match response {
-            ActionResponse::One(server_opt) => {              
-              if let Some(server) = server_opt{
-                  if server.domain.is_some() {
-                     match connect_terminal(server.user_name, server.domain.unwrap()){
-                        Ok(_) => Ok(ActionResponse::Done),
-                        Err(why) =>  {
-                            info!("Unable to connect {}",why);
-                            error
-                        }
-                     }                       
-                  }else if server.ip.is_some(){
-                    match connect_terminal(server.user_name, server.ip.unwrap()){
-                        Ok(_) => Ok(ActionResponse::Done),
-                        Err(why) =>  {
-                            info!("Unable to connect {}",why);
-                            error
-                        }
-                    }                     
-                  }else {
-                    error
-                  }                
-              }else{
-                error
-              }              
+
+/// Runs an action for `owner` against the given store
+pub fn route(repository: &dyn Repository, owner: &str, action: Action) -> Result<ActionResponse, ActionErr> {
+    match action {
+        Action::Save(server) => save(validate_server(server)?, repository, owner),
+        Action::SaveAll(servers) => save_all(validate_all(servers)?, repository, owner),
+        Action::Update(id, server) => {
+            update(CrudAction::Update(id, validate_server(server)?), "Update action failed", repository, owner)
+        }
+        Action::Fetch(query) => fetch(query, repository, owner),
+        Action::FetchById(id) => fetch_by_id(id, repository, owner),
+        Action::Delete => delete(repository, owner),
+        Action::DeleteById(id) => delete_by_id(id, repository, owner),
+        Action::FetchTrash => fetch_trash(repository, owner),
+        Action::Restore(id) => restore(id, repository, owner),
+        Action::PurgeTrash(older_than) => purge_trash(older_than, repository, owner),
+        Action::Connect(id, launch) => connect(id, launch, repository, owner),
+        Action::Resolve(name) => resolve(name, repository, owner),
+        Action::ResolveTrashed(input) => resolve_trashed(input, repository, owner),
+        Action::SetAttributes(id, attributes) => {
+            update(CrudAction::SetAttributes(id, attributes), "Set attributes action failed", repository, owner)
+        }
+        Action::RemoveAttributes(id, names) => {
+            update(CrudAction::RemoveAttributes(id, names), "Remove attributes action failed", repository, owner)
+        }
+        Action::SetNotes(id, notes) => {
+            update(CrudAction::SetNotes(id, notes), "Notes action failed", repository, owner)
+        }
+        Action::Diagnose => diagnose(repository, owner),
+        Action::Vacuum => match repository.execute(CrudAction::Vacuum, owner) {
+            Ok(_) => Ok(ActionResponse::Done),
+            Err(why) => Err(failed("Vacuum action failed", why)),
+        },
+        Action::Backup(target) => match repository.backup(&target) {
+            Ok(_) => Ok(ActionResponse::Done),
+            Err(why) => Err(failed("Backup action failed", why)),
+        },
+        Action::VerifyBackup(source) => match repository.verify_backup(&source) {
+            Ok(_) => Ok(ActionResponse::Done),
+            Err(why) => Err(failed("Backup is not restorable", why)),
+        },
+        Action::RestoreBackup(source) => match repository.restore(&source) {
+            Ok(_) => Ok(ActionResponse::Done),
+            Err(why) => Err(failed("Restore backup action failed", why)),
+        },
+        Action::AddUser(name) => add_user(name, repository, owner),
+        Action::FetchUsers => fetch_users(repository, owner),
+        Action::DeleteUser(name) => delete_user(name, repository, owner),
+        Action::Import(servers, policy, dry_run) => import(validate_all(servers)?, policy, dry_run, repository, owner),
+    }
+}
+
+/// Servers are the same when they log into the same host as the same user
+fn import_key(server: &Server) -> (String, String) {
+    let host = server
+        .domain
+        .as_ref()
+        .filter(|domain| !domain.is_empty())
+        .or(server.ip.as_ref())
+        .map(|host| host.to_lowercase())
+        .unwrap_or_default();
+    (host, server.user_name.to_owned())
+}
+
+fn import(
+    servers: Vec<Server>,
+    policy: ConflictPolicy,
+    dry_run: bool,
+    repository: &dyn Repository,
+    owner: &str,
+) -> Result<ActionResponse, ActionErr> {
+    let existing = match repository.execute(CrudAction::FindAll(ServerQuery::default()), owner) {
+        Ok(Response::List(existing)) => existing,
+        Ok(_) => return Err(unexpected("Import action failed")),
+        Err(why) => return Err(failed("Import action failed", why)),
+    };
+    let mut seen = BTreeMap::new();
+    let mut summary = ImportSummary::default();
+    let mut conflicts = Vec::new();
+    let mut actions = Vec::new();
+    for server in servers {
+        let key = import_key(&server);
+        if let Some(first) = seen.insert(key.clone(), server.title.to_owned()) {
+            return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+                "{} and {} both log into {} as {}",
+                first, server.title, key.0, key.1
+            ))));
+        }
+        match existing.iter().find(|current| import_key(current) == key) {
+            None => {
+                summary.added.push(server.title.to_owned());
+                actions.push(CrudAction::Save(server));
+            }
+            Some(current) => match policy {
+                ConflictPolicy::Upsert => {
+                    summary.updated.push(server.title.to_owned());
+                    // Fields the import leaves empty keep their current value
+                    let merged = Server {
+                        alias: server.alias.or_else(|| current.alias.clone()),
+                        domain: server.domain.or_else(|| current.domain.clone()),
+                        ip: server.ip.or_else(|| current.ip.clone()),
+                        port: server.port.or(current.port),
+                        notes: server.notes.or_else(|| current.notes.clone()),
+                        ..server
+                    };
+                    actions.push(CrudAction::Update(current.id.unwrap_or_default().into(), merged));
+                }
+                ConflictPolicy::Skip => summary.skipped.push(server.title),
+                ConflictPolicy::Fail => conflicts.push(format!("{} ({}@{})", server.title, key.1, key.0)),
             },
-            _ => error
         }
-    }else{
-        Err(ActionErr::build(ActionErrType::InitNotAvailable)) 
     }
-    
+    if !conflicts.is_empty() {
+        return Err(ActionErr::build(ActionErrType::InvalidInput(format!(
+            "{} server(s) are already in the inventory: {}; choose --on-conflict upsert or skip",
+            conflicts.len(),
+            conflicts.join(", ")
+        ))));
+    }
+    if !dry_run && !actions.is_empty() {
+        match repository.execute_all(actions, owner) {
+            Ok(responses) if responses.iter().any(|response| matches!(response, Response::One(None))) => {
+                return Err(unexpected("Import action failed, a server changed while importing"))
+            }
+            Ok(_) => (),
+            Err(why) => return Err(failed("Import action failed", why)),
+        }
+    }
+    Ok(ActionResponse::Imported(summary))
+}
+
+/// Wraps a store error into the error of the action that hit it
+fn failed(message: &str, why: DbError) -> ActionErr {
+    match why {
+        DbError::NotInitialized(_) => ActionErr::build(ActionErrType::InitNotAvailable).with_source(why),
+        _ => ActionErr::build(ActionErrType::ActionFailed(message.to_owned())).with_source(why),
+    }
+}
+
+fn unexpected(message: &str) -> ActionErr {
+    ActionErr::build(ActionErrType::ActionFailed(message.to_owned()))
 }
-fn save(server: Server, db: String) -> Result<ActionResponse, ActionErr> {
-    match db_action(CrudAction::Save(server), db) {
-        Response::Success => Ok(ActionResponse::Done),
-        _ => Err(ActionErr::build(ActionErrType::ActionFailed("Save action failed".to_owned())))
+
+fn connect(id: ID, launch: Launch, repository: &dyn Repository, owner: &str) -> Result<ActionResponse,ActionErr> {
+    let server = match fetch_by_id(id, repository, owner)? {
+        ActionResponse::One(Some(server)) => server,
+        _ => return Err(ActionErr::build(ActionErrType::RecordNotFound)),
+    };
+    let ip = server.ip.clone().filter(|ip| !ip.is_empty());
+    let host = server.domain.clone().filter(|domain| !domain.is_empty()).or(ip);
+    match host {
+        Some(host) => match connector::connect(&launch, &server, &host) {
+            Ok(_) => touch(id, repository, owner),
+            Err(why) => {
+                info!("Unable to connect {}", why);
+                Err(unexpected("Connect action failed").with_source(why))
+            }
+        },
+        None => Err(unexpected("Connect action failed, the server has neither a domain nor an ip")),
     }
-    
 }
-fn fetch(db: String) -> Result<ActionResponse, ActionErr> {
-    Ok(match db_action(CrudAction::FindAll, db) {
-        Response::List(result) => {
+/// Records the connection time used to sort by last-connected
+fn touch(id: ID, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    if let Err(why) = repository.execute(CrudAction::Touch(id), owner) {
+        info!("Unable to record the connection time {}", why);
+    }
+    Ok(ActionResponse::Done)
+}
+fn validate_all(servers: Vec<Server>) -> Result<Vec<Server>, ActionErr> {
+    servers.into_iter().map(validate_server).collect()
+}
+fn save(server: Server, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::Save(server), owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(_) => Err(unexpected("Save action failed")),
+        Err(why) => Err(failed("Save action failed", why)),
+    }
+}
+fn save_all(servers: Vec<Server>, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    let actions = servers.into_iter().map(CrudAction::Save).collect();
+    match repository.execute_all(actions, owner) {
+        Ok(_) => Ok(ActionResponse::Done),
+        Err(why) => Err(failed("Save action failed", why)),
+    }
+}
+fn fetch(query: ServerQuery, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::FindAll(query), owner) {
+        Ok(Response::List(result)) => {
             if result.is_empty(){
-                ActionResponse::Empty
+                Ok(ActionResponse::Empty)
             }else{
-                ActionResponse::All(result)
+                Ok(ActionResponse::All(result))
             }
         }
-        _ => ActionResponse::Empty,
-    })
+        Ok(_) => Err(unexpected("List action failed")),
+        Err(why) => Err(failed("List action failed", why)),
+    }
+}
+fn fetch_by_id(id: ID, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::Find(id), owner) {
+        Ok(Response::One(result)) => Ok(ActionResponse::One(result)),
+        Ok(_) => Err(unexpected("Find action failed")),
+        Err(why) => Err(failed("Find action failed", why)),
+    }
+}
+
+/// Finds the single server addressed by an alias or a (prefix of a) title
+fn resolve(name: String, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    let name = name.trim().to_owned();
+    match repository.execute(CrudAction::FindByAlias(name.clone()), owner) {
+        Ok(Response::One(Some(server))) => return Ok(ActionResponse::One(Some(server))),
+        Ok(Response::One(None)) => (),
+        Ok(_) => return Err(unexpected("Resolve action failed")),
+        Err(why) => return Err(failed("Resolve action failed", why)),
+    }
+    match repository.execute(CrudAction::FindByName(name), owner) {
+        Ok(Response::List(mut candidates)) => match candidates.len() {
+            0 => Err(ActionErr::build(ActionErrType::RecordNotFound)),
+            1 => Ok(ActionResponse::One(candidates.pop())),
+            _ => Err(ActionErr::build(ActionErrType::AmbiguousRecord(candidates))),
+        },
+        Ok(_) => Err(unexpected("Resolve action failed")),
+        Err(why) => Err(failed("Resolve action failed", why)),
+    }
+}
+
+/// Finds the single trashed server addressed by an id, an alias or a (prefix
+/// of a) title, trying them in that order
+fn resolve_trashed(input: String, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    let trashed = match repository.execute(CrudAction::FindDeleted, owner) {
+        Ok(Response::List(trashed)) => trashed,
+        Ok(_) => return Err(unexpected("Resolve action failed")),
+        Err(why) => return Err(failed("Resolve action failed", why)),
+    };
+    let input = input.trim();
+    let id = input.parse::<ID>().ok();
+    let prefix = input.to_lowercase();
+    let starts = |value: &str| value.to_lowercase().starts_with(&prefix);
+    let rules: [&dyn Fn(&Server) -> bool; 4] = [
+        &|server| id.is_some() && server.id.map(ID::from) == id,
+        &|server| server.alias.as_deref() == Some(input),
+        &|server| server.title == input,
+        &|server| starts(&server.title) || server.alias.as_deref().is_some_and(starts),
+    ];
+    for rule in rules {
+        let mut candidates: Vec<Server> = trashed.iter().filter(|server| rule(server)).cloned().collect();
+        match candidates.len() {
+            0 => continue,
+            1 => return Ok(ActionResponse::One(candidates.pop())),
+            _ => return Err(ActionErr::build(ActionErrType::AmbiguousRecord(candidates))),
+        }
+    }
+    Err(ActionErr::build(ActionErrType::RecordNotFound))
+}
+
+fn delete(repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::RemoveAll, owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(_) => Err(unexpected("Remove action failed")),
+        Err(why) => Err(failed("Remove action failed", why)),
+    }
+}
+
+fn delete_by_id(id: ID, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::Remove(id), owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(Response::One(None)) => Err(ActionErr::build(ActionErrType::RecordNotFound)),
+        Ok(_) => Err(unexpected("Remove action failed")),
+        Err(why) => Err(failed("Remove action failed", why)),
+    }
+}
+
+fn fetch_trash(repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::FindDeleted, owner) {
+        Ok(Response::List(result)) if result.is_empty() => Ok(ActionResponse::Empty),
+        Ok(Response::List(result)) => Ok(ActionResponse::All(result)),
+        Ok(_) => Err(unexpected("List trash action failed")),
+        Err(why) => Err(failed("List trash action failed", why)),
+    }
+}
+
+fn restore(id: ID, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::Restore(id), owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(Response::One(None)) => Err(ActionErr::build(ActionErrType::RecordNotFound)),
+        Ok(_) => Err(unexpected("Restore action failed")),
+        Err(why) => Err(failed("Restore action failed", why)),
+    }
+}
+
+fn purge_trash(older_than: Option<i64>, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::Purge(older_than), owner) {
+        Ok(Response::Purged(count)) => Ok(ActionResponse::Purged(count)),
+        Ok(_) => Err(unexpected("Purge action failed")),
+        Err(why) => Err(failed("Purge action failed", why)),
+    }
+}
+
+/// Purges the trash along with the vault secrets of the purged servers, as
+/// their ids go to the next servers added
+fn purge_with_secrets(
+    older_than: Option<i64>,
+    repository: &dyn Repository,
+    owner: &str,
+    vault_path: &str,
+) -> Result<ActionResponse, ActionErr> {
+    let trashed = || -> Result<BTreeSet<ID>, ActionErr> {
+        match fetch_trash(repository, owner)? {
+            ActionResponse::All(servers) => Ok(servers.iter().filter_map(|server| server.id).map(ID::from).collect()),
+            _ => Ok(BTreeSet::new()),
+        }
+    };
+    let before = trashed()?;
+    let response = purge_trash(older_than, repository, owner)?;
+    let forget_failed = |why: VaultError| {
+        ActionErr::build(ActionErrType::ActionFailed("Unable to remove the secrets of the purged servers".to_owned()))
+            .with_source(why)
+    };
+    for id in before.difference(&trashed()?) {
+        if vault::has_secret(vault_path, *id).map_err(forget_failed)? {
+            vault::remove_secret(vault_path, *id).map_err(forget_failed)?;
+        }
+    }
+    Ok(response)
+}
+
+/// Runs a change to one server, which has to be live
+fn update(action: CrudAction, message: &str, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(action, owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(Response::One(None)) => Err(ActionErr::build(ActionErrType::RecordNotFound)),
+        Ok(_) => Err(unexpected(message)),
+        Err(why) => Err(failed(message, why)),
+    }
+}
+
+/// Checks of the store, plus whether the configured user exists in it
+fn diagnose(repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    let mut findings = match repository.execute(CrudAction::Diagnose, owner) {
+        Ok(Response::Report(findings)) => findings,
+        Ok(_) => return Err(unexpected("Diagnose action failed")),
+        Err(why) => return Err(failed("Diagnose action failed", why)),
+    };
+    match repository.execute(CrudAction::FindUsers, owner) {
+        Ok(Response::Users(users)) if users.iter().any(|user| user.name == owner) => {
+            findings.push(Finding::ok("user", format!("User {} exists", owner)))
+        }
+        Ok(_) => findings.push(Finding::error(
+            "user",
+            format!("Configured user {} does not exist", owner),
+            format!("Add it with `rusteze user add {}` or change user in the config", owner),
+        )),
+        Err(why) => return Err(failed("Diagnose action failed", why)),
+    }
+    Ok(ActionResponse::Report(findings))
 }
-fn fetch_by_id(id: ID, db: String) -> Result<ActionResponse, ActionErr> {
-    Ok(match db_action(CrudAction::Find(id), db) {
-        Response::One(result) => ActionResponse::One(result),
-        _ => ActionResponse::Empty,
-    })
+
+fn add_user(name: String, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::SaveUser(name), owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(_) => Err(unexpected("Add user action failed")),
+        Err(why) => Err(failed("Add user action failed", why)),
+    }
 }
 
-fn delete(db: String) -> Result<ActionResponse, ActionErr> {
-    match db_action(CrudAction::RemoveAll, db) {
-        Response::Success => Ok(ActionResponse::Done),
-        _ => Err(ActionErr::build(ActionErrType::InitNotAvailable)),
+fn fetch_users(repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::FindUsers, owner) {
+        Ok(Response::Users(users)) => Ok(ActionResponse::Users(users)),
+        Ok(_) => Err(unexpected("List users action failed")),
+        Err(why) => Err(failed("List users action failed", why)),
     }
 }
 
-fn delete_by_id(id: ID, db: String) -> Result<ActionResponse, ActionErr> {
-    match db_action(CrudAction::Remove(id), db) {
-        Response::Success => Ok(ActionResponse::Done),
-        _ => Err(ActionErr::build(ActionErrType::InitNotAvailable)),
+fn delete_user(name: String, repository: &dyn Repository, owner: &str) -> Result<ActionResponse, ActionErr> {
+    match repository.execute(CrudAction::RemoveUser(name), owner) {
+        Ok(Response::Success) => Ok(ActionResponse::Done),
+        Ok(_) => Err(unexpected("Remove user action failed")),
+        Err(why) => Err(failed("Remove user action failed", why)),
     }
 }
+
+#[test]
+fn route_resolve_test() {
+    use crate::memory::MemoryRepository;
+
+    let repository = MemoryRepository::new();
+    let owner = crate::domain::DEFAULT_USER;
+    for title in ["web-1", "web-2"] {
+        let server = Server::new(title.to_owned(), None, Some("10.0.0.1".to_owned()), "admin".to_owned());
+        assert_eq!(route(&repository, owner, Action::Save(server)).unwrap(), ActionResponse::Done);
+    }
+    match route(&repository, owner, Action::Resolve("web".to_owned())) {
+        Err(why) => assert!(matches!(why.error_type(), ActionErrType::AmbiguousRecord(candidates) if candidates.len() == 2)),
+        Ok(_) => panic!("expected an ambiguous match"),
+    }
+    match route(&repository, owner, Action::Resolve("web-2".to_owned())).unwrap() {
+        ActionResponse::One(Some(server)) => assert_eq!(server.title, "web-2"),
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert!(route(&repository, owner, Action::Resolve("db".to_owned())).is_err());
+}
+
+#[test]
+fn route_resolve_trashed_test() {
+    use crate::memory::MemoryRepository;
+
+    let repository = MemoryRepository::new();
+    let owner = crate::domain::DEFAULT_USER;
+    for (title, alias) in [("web-1", Some("front")), ("web-2", None), ("db", None)] {
+        let server = Server {
+            alias: alias.map(str::to_owned),
+            ..Server::new(title.to_owned(), None, Some("10.0.0.1".to_owned()), "admin".to_owned())
+        };
+        route(&repository, owner, Action::Save(server)).unwrap();
+    }
+    let title = |input: &str| match route(&repository, owner, Action::ResolveTrashed(input.to_owned())) {
+        Ok(ActionResponse::One(Some(server))) => Ok(server.title),
+        Ok(other) => panic!("unexpected response {:?}", other),
+        Err(why) => Err(why),
+    };
+    assert!(matches!(title("1").unwrap_err().error_type(), ActionErrType::RecordNotFound));
+    route(&repository, owner, Action::DeleteById(1)).unwrap();
+    route(&repository, owner, Action::DeleteById(2)).unwrap();
+    assert!(matches!(route(&repository, owner, Action::DeleteById(2)).unwrap_err().error_type(), ActionErrType::RecordNotFound));
+
+    assert_eq!(title("2").unwrap(), "web-2");
+    assert_eq!(title("front").unwrap(), "web-1");
+    assert_eq!(title("WEB-1").unwrap(), "web-1");
+    assert!(matches!(title("web").unwrap_err().error_type(), ActionErrType::AmbiguousRecord(candidates) if candidates.len() == 2));
+    // Live servers are not in the trash
+    assert!(matches!(title("db").unwrap_err().error_type(), ActionErrType::RecordNotFound));
+}
+
+#[test]
+fn route_import_test() {
+    use crate::memory::MemoryRepository;
+
+    let repository = MemoryRepository::new();
+    let owner = crate::domain::DEFAULT_USER;
+    let server = |title: &str, ip: &str, alias: Option<&str>| Server {
+        alias: alias.map(str::to_owned),
+        ..Server::new(title.to_owned(), None, Some(ip.to_owned()), "admin".to_owned())
+    };
+    route(&repository, owner, Action::Save(server("web", "10.0.0.1", Some("web")))).unwrap();
+    let count = || match route(&repository, owner, Action::Fetch(ServerQuery::default())).unwrap() {
+        ActionResponse::All(servers) => servers.len(),
+        _ => 0,
+    };
+    let batch = || vec![server("web-renamed", "10.0.0.1", None), server("db", "10.0.0.2", None)];
+
+    assert!(route(&repository, owner, Action::Import(batch(), ConflictPolicy::Fail, false)).is_err());
+    match route(&repository, owner, Action::Import(batch(), ConflictPolicy::Skip, true)).unwrap() {
+        ActionResponse::Imported(summary) => {
+            assert_eq!(summary.added, vec!["db".to_owned()]);
+            assert_eq!(summary.skipped, vec!["web-renamed".to_owned()]);
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert_eq!(count(), 1);
+    route(&repository, owner, Action::Import(batch(), ConflictPolicy::Upsert, false)).unwrap();
+    assert_eq!(count(), 2);
+
+    // A failing server leaves the whole batch out
+    let clashing = || vec![server("cache", "10.0.0.3", None), server("queue", "10.0.0.4", Some("web"))];
+    assert!(route(&repository, owner, Action::Import(clashing(), ConflictPolicy::Skip, false)).is_err());
+    assert_eq!(count(), 2);
+    assert!(route(&repository, owner, Action::SaveAll(clashing())).is_err());
+    assert_eq!(count(), 2);
+
+    // Every write checks the servers, not only the command line
+    let option = || server("proxy", "-oProxyCommand=id", None);
+    assert!(route(&repository, owner, Action::Save(option())).is_err());
+    assert!(route(&repository, owner, Action::SaveAll(vec![option()])).is_err());
+    assert!(route(&repository, owner, Action::Import(vec![option()], ConflictPolicy::Skip, false)).is_err());
+    assert!(route(&repository, owner, Action::Update(1, option())).is_err());
+    assert_eq!(count(), 2);
+}
+
+#[test]
+fn purge_secrets_test() {
+    use crate::memory::MemoryRepository;
+
+    let repository = MemoryRepository::new();
+    let owner = crate::domain::DEFAULT_USER;
+    for title in ["web", "db"] {
+        let server = Server::new(title.to_owned(), None, Some("10.0.0.1".to_owned()), "admin".to_owned());
+        route(&repository, owner, Action::Save(server)).unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("rusteze-purge-test-{}.vault", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let mut secrets = vault::Vault::create(&path, "correct horse").unwrap();
+    secrets.set(1, "web secret").unwrap();
+    secrets.set(2, "db secret").unwrap();
+
+    route(&repository, owner, Action::DeleteById(1)).unwrap();
+    assert_eq!(purge_with_secrets(None, &repository, owner, &path).unwrap(), ActionResponse::Purged(1));
+    assert_eq!(vault::secret_ids(&path).unwrap(), vec![2]);
+    std::fs::remove_file(&path).unwrap();
+}
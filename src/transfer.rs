@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::domain::{ActionErr, ActionErrType, OutputFormat, Server};
+
+/// Columns of an exported csv before the `attr.<name>` ones
+const CSV_COLUMNS: [&str; 7] = ["title", "alias", "domain", "ip", "port", "user", "notes"];
+
+/// Dumps servers in a format `parse` reads back, json keeping every field and
+/// csv turning the attributes into `attr.<name>` columns
+pub fn export(servers: &[Server], format: OutputFormat) -> Result<String, ActionErr> {
+    match format {
+        OutputFormat::Json => serde_json::to_string_pretty(servers)
+            .map(|json| json + "\n")
+            .map_err(|why| export_failed(why.to_string())),
+        OutputFormat::Csv => export_csv(servers),
+        _ => Err(unsupported(format)),
+    }
+}
+
+fn export_csv(servers: &[Server]) -> Result<String, ActionErr> {
+    let attributes: BTreeSet<&String> = servers.iter().flat_map(|server| server.attributes.keys()).collect();
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let header = CSV_COLUMNS
+        .iter()
+        .map(|column| column.to_string())
+        .chain(attributes.iter().map(|name| format!("attr.{}", name)));
+    writer.write_record(header).map_err(|why| export_failed(why.to_string()))?;
+    for server in servers {
+        let optional = |value: &Option<String>| value.to_owned().unwrap_or_default();
+        let record = vec![
+            server.title.to_owned(),
+            optional(&server.alias),
+            optional(&server.domain),
+            optional(&server.ip),
+            server.port.map(|port| port.to_string()).unwrap_or_default(),
+            server.user_name.to_owned(),
+            optional(&server.notes),
+        ]
+        .into_iter()
+        .chain(attributes.iter().map(|name| server.attributes.get(*name).cloned().unwrap_or_default()));
+        writer.write_record(record).map_err(|why| export_failed(why.to_string()))?;
+    }
+    let content = writer.into_inner().map_err(|why| export_failed(why.to_string()))?;
+    String::from_utf8(content).map_err(|why| export_failed(why.to_string()))
+}
+
+/// Reads servers written by `export`. Ids, owners and timestamps are left
+/// out, the importing user owns the servers.
+pub fn parse(content: &str, format: OutputFormat) -> Result<Vec<Server>, ActionErr> {
+    let servers = match format {
+        OutputFormat::Json => serde_json::from_str::<Vec<Server>>(content).map_err(|why| {
+            ActionErr::build(ActionErrType::InvalidInput("Invalid json import".to_owned())).with_source(why)
+        })?,
+        OutputFormat::Csv => parse_csv(content)?,
+        _ => return Err(unsupported(format)),
+    };
+    Ok(servers
+        .into_iter()
+        .map(|server| Server {
+            id: None,
+            owner: None,
+            last_connected: None,
+            deleted_at: None,
+            ..server
+        })
+        .collect())
+}
+
+fn parse_csv(content: &str) -> Result<Vec<Server>, ActionErr> {
+    let invalid = |message: String| ActionErr::build(ActionErrType::InvalidInput(message));
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let header = reader
+        .headers()
+        .map_err(|why| invalid("Invalid csv import".to_owned()).with_source(why))?
+        .clone();
+    if let Some(unknown) = header
+        .iter()
+        .find(|column| !CSV_COLUMNS.contains(column) && !column.starts_with("attr."))
+    {
+        return Err(invalid(format!(
+            "Unknown column {}, expected {} or attr.<name>",
+            unknown,
+            CSV_COLUMNS.join(", ")
+        )));
+    }
+    let mut servers = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+        // The header is line 1
+        let line = index + 2;
+        let record = record.map_err(|why| invalid(format!("Invalid csv line {}", line)).with_source(why))?;
+        let mut fields: BTreeMap<&str, String> = BTreeMap::new();
+        let mut attributes = BTreeMap::new();
+        for (column, value) in header.iter().zip(record.iter()) {
+            match column.strip_prefix("attr.") {
+                Some(name) if !value.is_empty() => {
+                    attributes.insert(name.to_owned(), value.to_owned());
+                }
+                Some(_) => (),
+                None => {
+                    fields.insert(column, value.to_owned());
+                }
+            }
+        }
+        let mut field = |name: &str| fields.remove(name).filter(|value| !value.is_empty());
+        let port = match field("port") {
+            Some(port) => Some(port.parse::<u16>().map_err(|why| {
+                invalid(format!("Invalid port {} on line {}", port, line)).with_source(why)
+            })?),
+            None => None,
+        };
+        servers.push(Server {
+            alias: field("alias"),
+            port,
+            notes: field("notes"),
+            attributes,
+            ..Server::new(
+                field("title").unwrap_or_default(),
+                field("domain"),
+                field("ip"),
+                field("user").unwrap_or_default(),
+            )
+        });
+    }
+    Ok(servers)
+}
+
+fn export_failed(why: String) -> ActionErr {
+    ActionErr::build(ActionErrType::ActionFailed("Unable to export the servers".to_owned())).with_source(why)
+}
+
+fn unsupported(format: OutputFormat) -> ActionErr {
+    ActionErr::build(ActionErrType::InvalidInput(format!(
+        "{} is not an import or export format, use json or csv",
+        format.name()
+    )))
+}
+
+#[test]
+fn csv_roundtrip_test() {
+    let mut web = Server::new("web".to_owned(), Some("web.example.com".to_owned()), None, "admin".to_owned());
+    web.port = Some(2222);
+    web.notes = Some("restart with care, then \"check\"".to_owned());
+    web.attributes.insert("dc".to_owned(), "ams".to_owned());
+    let db = Server {
+        alias: Some("db".to_owned()),
+        ..Server::new("db".to_owned(), None, Some("10.0.0.9".to_owned()), "root".to_owned())
+    };
+    let exported = export(&[web.clone(), db.clone()], OutputFormat::Csv).unwrap();
+    assert!(exported.starts_with("title,alias,domain,ip,port,user,notes,attr.dc\n"));
+
+    let imported = parse(&exported, OutputFormat::Csv).unwrap();
+    let expected = |server: Server| Server { owner: None, ..server };
+    assert_eq!(imported, vec![expected(web), expected(db)]);
+    assert!(parse("title,colour\nweb,red\n", OutputFormat::Csv).is_err());
+}
@@ -0,0 +1,463 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::domain::{now, ID};
+
+const VAULT_VERSION: u32 = 1;
+/// Plain text sealed with the key so a wrong passphrase is told apart from a damaged secret
+const CHECK_TEXT: &[u8] = b"rusteze vault";
+const ASKPASS_VAULT: &str = "RUSTEZE_ASKPASS_VAULT";
+const ASKPASS_SERVER: &str = "RUSTEZE_ASKPASS_SERVER";
+
+/// Server passwords and key passphrases encrypted with a key derived from a
+/// master passphrase. Only the secrets are encrypted, the server ids they
+/// belong to are readable so a secret can be removed without unlocking.
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    kdf: KdfParams,
+    salt: String,
+    check: Sealed,
+    #[serde(default)]
+    secrets: BTreeMap<ID, Sealed>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    data: String,
+}
+
+/// Key cached by `secret unlock` until it expires or `secret lock` runs
+#[derive(Serialize, Deserialize)]
+struct Session {
+    key: String,
+    expires_at: i64,
+}
+
+#[derive(Debug)]
+pub enum VaultError {
+    /// No secret was ever stored, so there is no vault file
+    NotFound(String),
+    WrongPassphrase,
+    /// ssh asked for a secret while no invocation holds the vault unlocked
+    Locked,
+    Crypto(String),
+    Io(io::Error),
+    Format(serde_json::Error),
+}
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VaultError::NotFound(path) => write!(f, "Vault {} is not found", path),
+            VaultError::WrongPassphrase => write!(f, "Wrong vault passphrase"),
+            VaultError::Locked => write!(f, "Vault is locked, run `rusteze secret unlock`"),
+            VaultError::Crypto(why) => write!(f, "Vault encryption error: {}", why),
+            VaultError::Io(why) => write!(f, "Vault file error: {}", why),
+            VaultError::Format(why) => write!(f, "Vault file is not valid: {}", why),
+        }
+    }
+}
+impl error::Error for VaultError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VaultError::Io(why) => Some(why),
+            VaultError::Format(why) => Some(why),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for VaultError {
+    fn from(why: io::Error) -> Self {
+        VaultError::Io(why)
+    }
+}
+impl From<serde_json::Error> for VaultError {
+    fn from(why: serde_json::Error) -> Self {
+        VaultError::Format(why)
+    }
+}
+
+type Result<T> = std::result::Result<T, VaultError>;
+
+/// An unlocked vault
+pub struct Vault {
+    path: String,
+    file: VaultFile,
+    cipher: ChaCha20Poly1305,
+    key: [u8; 32],
+}
+
+impl Vault {
+    pub fn exists(path: &str) -> bool {
+        Path::new(path).exists()
+    }
+
+    /// Creates an empty vault protected by `passphrase`
+    pub fn create(path: &str, passphrase: &str) -> Result<Vault> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let defaults = Params::default();
+        let kdf = KdfParams {
+            memory: defaults.m_cost(),
+            iterations: defaults.t_cost(),
+            parallelism: defaults.p_cost(),
+        };
+        let key = derive_key(passphrase, &salt, &kdf)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let check = seal(&cipher, CHECK_TEXT, b"check")?;
+        let vault = Vault {
+            path: path.to_owned(),
+            file: VaultFile {
+                version: VAULT_VERSION,
+                kdf,
+                salt: to_hex(&salt),
+                check,
+                secrets: BTreeMap::new(),
+            },
+            cipher,
+            key,
+        };
+        vault.save()?;
+        Ok(vault)
+    }
+
+    pub fn unlock(path: &str, passphrase: &str) -> Result<Vault> {
+        let file = load(path)?;
+        let key = derive_key(passphrase, &from_hex(&file.salt)?, &file.kdf)?;
+        Vault::with_key(path, file, key)
+    }
+
+    /// The vault unlocked by an earlier invocation, if its session is still valid
+    pub fn from_session(path: &str) -> Result<Option<Vault>> {
+        let session_path = session_path(path)?;
+        let session: Session = match fs::read_to_string(&session_path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(why) => return Err(why.into()),
+        };
+        if session.expires_at <= now() {
+            fs::remove_file(&session_path)?;
+            return Ok(None);
+        }
+        let mut key = [0u8; 32];
+        let cached = from_hex(&session.key)?;
+        if cached.len() != key.len() {
+            return Ok(None);
+        }
+        key.copy_from_slice(&cached);
+        match Vault::with_key(path, load(path)?, key) {
+            Ok(vault) => Ok(Some(vault)),
+            // The vault was recreated since the session started
+            Err(VaultError::WrongPassphrase) => Ok(None),
+            Err(why) => Err(why),
+        }
+    }
+
+    fn with_key(path: &str, file: VaultFile, key: [u8; 32]) -> Result<Vault> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        match open(&cipher, &file.check, b"check") {
+            Ok(check) if check == CHECK_TEXT => Ok(Vault {
+                path: path.to_owned(),
+                file,
+                cipher,
+                key,
+            }),
+            _ => Err(VaultError::WrongPassphrase),
+        }
+    }
+
+    /// Keeps the vault unlocked for `ttl` seconds for later invocations
+    pub fn remember(&self, ttl: i64) -> Result<()> {
+        let session = Session {
+            key: to_hex(&self.key),
+            expires_at: now() + ttl,
+        };
+        write_private(&session_path(&self.path)?, &serde_json::to_string(&session)?)?;
+        Ok(())
+    }
+
+    /// Drops the cached key, the next use asks for the passphrase again
+    pub fn forget(path: &str) -> Result<()> {
+        match fs::remove_file(session_path(path)?) {
+            Err(why) if why.kind() != io::ErrorKind::NotFound => Err(why.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set(&mut self, server: ID, secret: &str) -> Result<()> {
+        let sealed = seal(&self.cipher, secret.as_bytes(), server.to_string().as_bytes())?;
+        self.file.secrets.insert(server, sealed);
+        self.save()
+    }
+
+    pub fn get(&self, server: ID) -> Result<Option<String>> {
+        match self.file.secrets.get(&server) {
+            Some(sealed) => {
+                let secret = open(&self.cipher, sealed, server.to_string().as_bytes())?;
+                String::from_utf8(secret)
+                    .map(Some)
+                    .map_err(|why| VaultError::Crypto(why.to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        write_private(Path::new(&self.path), &serde_json::to_string_pretty(&self.file)?)?;
+        Ok(())
+    }
+}
+
+/// Whether a secret is stored for the server, without unlocking the vault
+pub fn has_secret(path: &str, server: ID) -> Result<bool> {
+    if !Vault::exists(path) {
+        return Ok(false);
+    }
+    Ok(load(path)?.secrets.contains_key(&server))
+}
+
+/// Servers with a stored secret, without unlocking the vault
+pub fn secret_ids(path: &str) -> Result<Vec<ID>> {
+    if !Vault::exists(path) {
+        return Ok(Vec::new());
+    }
+    Ok(load(path)?.secrets.keys().copied().collect())
+}
+
+/// Removes the secret of a server, returns false when it had none
+pub fn remove_secret(path: &str, server: ID) -> Result<bool> {
+    let mut file = load(path)?;
+    if file.secrets.remove(&server).is_none() {
+        return Ok(false);
+    }
+    write_private(Path::new(path), &serde_json::to_string_pretty(&file)?)?;
+    Ok(true)
+}
+
+/// Lets ssh ask rusteze for the password of a server through SSH_ASKPASS,
+/// so the secret never shows up in a command line or the environment
+pub struct Askpass {
+    vault: PathBuf,
+    server: ID,
+}
+
+impl Askpass {
+    pub fn new(vault: &str, server: ID) -> Result<Askpass> {
+        Ok(Askpass {
+            vault: fs::canonicalize(vault)?,
+            server,
+        })
+    }
+
+    /// Variables to run ssh with
+    pub fn environment(&self) -> Result<Vec<(String, String)>> {
+        let program = env::current_exe()?;
+        Ok(vec![
+            ("SSH_ASKPASS".to_owned(), program.to_string_lossy().into_owned()),
+            ("SSH_ASKPASS_REQUIRE".to_owned(), "force".to_owned()),
+            (ASKPASS_VAULT.to_owned(), self.vault.to_string_lossy().into_owned()),
+            (ASKPASS_SERVER.to_owned(), self.server.to_string()),
+        ])
+    }
+
+    /// When ssh started this process as its askpass program, the secret to
+    /// answer with. The vault has to be unlocked by the invocation running ssh.
+    pub fn requested() -> Option<Result<String>> {
+        let vault = env::var(ASKPASS_VAULT).ok()?;
+        let server = env::var(ASKPASS_SERVER).ok()?.parse::<ID>().ok()?;
+        Some(match Vault::from_session(&vault) {
+            Ok(Some(unlocked)) => match unlocked.get(server) {
+                Ok(Some(secret)) => Ok(secret),
+                Ok(None) => Err(VaultError::NotFound(format!("{} secret {}", vault, server))),
+                Err(why) => Err(why),
+            },
+            Ok(None) => Err(VaultError::Locked),
+            Err(why) => Err(why),
+        })
+    }
+}
+
+fn load(path: &str) -> Result<VaultFile> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(why) if why.kind() == io::ErrorKind::NotFound => Err(VaultError::NotFound(path.to_owned())),
+        Err(why) => Err(why.into()),
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], kdf: &KdfParams) -> Result<[u8; 32]> {
+    let params = Params::new(kdf.memory, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|why| VaultError::Crypto(why.to_string()))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|why| VaultError::Crypto(why.to_string()))?;
+    Ok(key)
+}
+
+/// Encrypts `plain`, authenticating `context` along with it so a secret
+/// moved to another server id no longer opens
+fn seal(cipher: &ChaCha20Poly1305, plain: &[u8], context: &[u8]) -> Result<Sealed> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let data = cipher
+        .encrypt(&nonce, Payload { msg: plain, aad: context })
+        .map_err(|why| VaultError::Crypto(why.to_string()))?;
+    Ok(Sealed {
+        nonce: to_hex(&nonce),
+        data: to_hex(&data),
+    })
+}
+
+fn open(cipher: &ChaCha20Poly1305, sealed: &Sealed, context: &[u8]) -> Result<Vec<u8>> {
+    let nonce = from_hex(&sealed.nonce)?;
+    if nonce.len() != 12 {
+        return Err(VaultError::Crypto("invalid nonce".to_owned()));
+    }
+    let data = from_hex(&sealed.data)?;
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &data, aad: context })
+        .map_err(|why| VaultError::Crypto(why.to_string()))
+}
+
+/// Session files live in the per user runtime directory when there is one,
+/// otherwise in a directory of the temporary one that only the owner of the
+/// vault can enter
+fn session_path(vault: &str) -> Result<PathBuf> {
+    let vault = fs::canonicalize(vault)?;
+    let mut hasher = DefaultHasher::new();
+    vault.hash(&mut hasher);
+    let directory = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) => PathBuf::from(directory),
+        None => private_directory(&vault)?,
+    };
+    Ok(directory.join(format!("rusteze-{:016x}.session", hasher.finish())))
+}
+
+#[cfg(unix)]
+fn private_directory(vault: &Path) -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    let owner = fs::metadata(vault)?.uid();
+    let directory = env::temp_dir().join(format!("rusteze-{}", owner));
+    match fs::DirBuilder::new().mode(0o700).create(&directory) {
+        Err(why) if why.kind() != io::ErrorKind::AlreadyExists => return Err(why.into()),
+        _ => (),
+    }
+    // Someone else may have made it first, or left a link there
+    let metadata = fs::symlink_metadata(&directory)?;
+    if !metadata.is_dir() || metadata.uid() != owner || metadata.permissions().mode() & 0o077 != 0 {
+        return Err(VaultError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a private directory", directory.display()),
+        )));
+    }
+    Ok(directory)
+}
+
+#[cfg(not(unix))]
+fn private_directory(_vault: &Path) -> Result<PathBuf> {
+    Ok(env::temp_dir())
+}
+
+/// Writes a file only the current user can read
+fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    // Never write through a file left by an interrupted write or planted there
+    match fs::remove_file(&temporary) {
+        Err(why) if why.kind() != io::ErrorKind::NotFound => return Err(why),
+        _ => (),
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temporary)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>> {
+    let invalid = || VaultError::Crypto("invalid hex value".to_owned());
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| {
+            text.get(index..index + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+#[test]
+fn vault_roundtrip_test() {
+    let path = env::temp_dir().join(format!("rusteze-vault-test-{}.vault", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    let mut vault = Vault::create(&path, "correct horse").unwrap();
+    vault.set(7, "s3cret").unwrap();
+
+    assert!(matches!(Vault::unlock(&path, "wrong"), Err(VaultError::WrongPassphrase)));
+    let vault = Vault::unlock(&path, "correct horse").unwrap();
+    assert_eq!(vault.get(7).unwrap().as_deref(), Some("s3cret"));
+    assert_eq!(vault.get(8).unwrap(), None);
+
+    // A secret copied onto another server id fails authentication
+    let mut file = load(&path).unwrap();
+    let sealed = file.secrets.remove(&7).unwrap();
+    assert!(open(&vault.cipher, &sealed, b"8").is_err());
+
+    assert!(has_secret(&path, 7).unwrap());
+    assert!(remove_secret(&path, 7).unwrap());
+    assert!(!has_secret(&path, 7).unwrap());
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn write_private_test() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = env::temp_dir().join(format!("rusteze-private-test-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("secret.session");
+    let planted = directory.join("planted");
+    fs::write(&planted, "").unwrap();
+    fs::set_permissions(&planted, fs::Permissions::from_mode(0o644)).unwrap();
+    std::os::unix::fs::symlink(&planted, path.with_extension("tmp")).unwrap();
+
+    write_private(&path, "key").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "key");
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::read_to_string(&planted).unwrap(), "");
+    fs::remove_dir_all(&directory).unwrap();
+}